@@ -0,0 +1,73 @@
+// Backend module: object storage abstraction for repository destinations
+//
+// A repository is a set of objects addressed by `/`-separated keys relative
+// to its root, e.g. `config.json` or `packs/ab/ab12...pack`. Backends map
+// keys onto a concrete destination (a local directory, an SFTP server, ...)
+// so repository logic never touches the filesystem directly.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Metadata of a stored object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectInfo {
+    pub size: u64,
+}
+
+/// Storage destination of a repository.
+pub trait Backend: Send + Sync {
+    /// Human-readable location of the repository root, for messages.
+    fn location(&self) -> String;
+
+    /// Store `data` under `key`, replacing any existing object.
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// How many objects `put_all` stores at the same time. Callers with
+    /// many objects to write batch at least this many per call.
+    fn concurrency(&self) -> usize {
+        1
+    }
+
+    /// Store several `(key, data)` objects, possibly concurrently. On error
+    /// some of them may have been stored.
+    fn put_all(&self, objects: &[(String, Vec<u8>)]) -> io::Result<()> {
+        for (key, data) in objects {
+            self.put(key, data)?;
+        }
+        Ok(())
+    }
+
+    /// Read a whole object.
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Read `length` bytes of an object starting at `offset`.
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> io::Result<Vec<u8>>;
+
+    /// Keys of all objects under `prefix`, recursively, in no particular
+    /// order. A missing prefix yields an empty list.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Delete an object.
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Metadata of an object. Fails with `NotFound` if there is none.
+    fn stat(&self, key: &str) -> io::Result<ObjectInfo>;
+
+    /// Whether an object exists under `key`.
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        match self.stat(key) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Unique sibling of `path` to write an object to before renaming it into
+/// place. Repository listings only match known extensions, so the `.tmp`
+/// suffix keeps leftovers of interrupted writes out of them.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}.tmp", name, Uuid::new_v4().simple()))
+}
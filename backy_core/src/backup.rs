@@ -0,0 +1,242 @@
+// Backup engine: walk source trees, chunk and encrypt files, write a snapshot
+
+use crate::blob_id::BlobId;
+use crate::chunker::chunk_file;
+use crate::repository::Repository;
+use crate::snapshot::{Node, NodeKind, Snapshot, Tree, save_snapshot, save_tree};
+use std::{fs, io, path::Path};
+
+/// Counters reported at the end of a backup.
+#[derive(Debug, Clone, Default)]
+pub struct BackupSummary {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,
+    pub chunks: u64,
+    /// Chunks that were not already in the repository.
+    pub new_chunks: u64,
+}
+
+struct Backup<'a> {
+    repo: &'a Repository,
+    summary: BackupSummary,
+}
+
+impl Backup<'_> {
+    fn node(&mut self, path: &Path) -> io::Result<Option<NodeKind>> {
+        let meta = fs::symlink_metadata(path)?;
+        if meta.is_dir() {
+            let subtree = self.dir(path)?;
+            Ok(Some(NodeKind::Dir { subtree }))
+        } else if meta.is_file() {
+            Ok(Some(self.file(path)?))
+        } else {
+            // Symlinks and special files are not backed up.
+            Ok(None)
+        }
+    }
+
+    fn dir(&mut self, path: &Path) -> io::Result<BlobId> {
+        let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        let mut tree = Tree::default();
+        for entry in entries {
+            if let Some(kind) = self.node(&entry.path())? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                tree.nodes.push(Node { name, kind });
+            }
+        }
+        self.summary.dirs += 1;
+        save_tree(self.repo, &tree)
+    }
+
+    fn file(&mut self, path: &Path) -> io::Result<NodeKind> {
+        let mut size = 0;
+        let mut chunks = Vec::new();
+        for chunk in chunk_file(path)? {
+            let chunk = chunk?;
+            self.repo.refresh_locks()?;
+            let id = self.repo.blob_id(&chunk.data);
+            if self.repo.save_blob_with_id(id, &chunk.data)? {
+                self.summary.new_chunks += 1;
+            }
+            size += chunk.length as u64;
+            chunks.push(id);
+        }
+        self.summary.files += 1;
+        self.summary.bytes += size;
+        self.summary.chunks += chunks.len() as u64;
+        Ok(NodeKind::File { size, chunks })
+    }
+}
+
+/// `name`, or `name (2)`, `name (3)`, ... if the root tree already has an
+/// entry called `name`.
+fn unique_name(root: &Tree, name: String) -> String {
+    let taken = |candidate: &str| root.nodes.iter().any(|n| n.name == candidate);
+    if !taken(&name) {
+        return name;
+    }
+    (2..).map(|n| format!("{} ({})", name, n)).find(|c| !taken(c)).unwrap()
+}
+
+/// Back up `sources` recursively into `repo`.
+/// Each source becomes an entry of the snapshot's root tree, named after its
+/// last path component; sources sharing that name get a ` (2)`, ` (3)`, ...
+/// suffix in the order given. A source listed twice is backed up once.
+/// Returns the saved snapshot and backup counters.
+/// Holds a shared lock on the repository while running, so several backups
+/// may write to it at once.
+pub fn backup(
+    repo: &Repository,
+    sources: &[&Path],
+    tags: Vec<String>,
+) -> io::Result<(Snapshot, BackupSummary)> {
+    let _lock = repo.lock_shared()?;
+    let result = backup_locked(repo, sources, tags);
+    if result.is_err() {
+        // No snapshot refers to the blobs still buffered. Drop them now
+        // rather than have them written without the lock.
+        repo.discard_pending();
+    }
+    result
+}
+
+fn backup_locked(
+    repo: &Repository,
+    sources: &[&Path],
+    tags: Vec<String>,
+) -> io::Result<(Snapshot, BackupSummary)> {
+    let mut backup = Backup { repo, summary: BackupSummary::default() };
+    let mut root = Tree::default();
+    let mut paths = Vec::new();
+    for source in sources {
+        let source = fs::canonicalize(source)?;
+        let path = source.to_string_lossy().into_owned();
+        if paths.contains(&path) {
+            continue;
+        }
+        let name = source
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "root".to_string());
+        if let Some(kind) = backup.node(&source)? {
+            root.nodes.push(Node { name: unique_name(&root, name), kind });
+        }
+        paths.push(path);
+    }
+    root.nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    let tree = save_tree(repo, &root)?;
+    let snapshot = Snapshot::new(paths, tags, tree);
+    save_snapshot(repo, &snapshot)?;
+    Ok((snapshot, backup.summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::STALE_AFTER;
+    use crate::snapshot::load_tree;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_backup_recurses_and_dedups() -> io::Result<()> {
+        let src = tempdir()?;
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        fs::create_dir_all(src.path().join("sub/deeper"))?;
+        fs::write(src.path().join("a.txt"), b"same content")?;
+        fs::write(src.path().join("sub/deeper/b.txt"), b"same content")?;
+
+        let (snapshot, summary) = backup(&repo, &[src.path()], Vec::new())?;
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.dirs, 3);
+        assert_eq!(summary.chunks, 2);
+        assert_eq!(summary.new_chunks, 1);
+
+        let root = load_tree(&repo, &snapshot.tree)?;
+        assert_eq!(root.nodes.len(), 1);
+        let NodeKind::Dir { subtree } = &root.nodes[0].kind else { panic!("expected dir") };
+        let top = load_tree(&repo, subtree)?;
+        let names: Vec<_> = top.nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, ["a.txt", "sub"]);
+
+        let (_, again) = backup(&repo, &[src.path()], Vec::new())?;
+        assert_eq!(again.new_chunks, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sources_with_the_same_name() -> io::Result<()> {
+        let a = tempdir()?;
+        let b = tempdir()?;
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        for (dir, content) in [(&a, "first"), (&b, "second")] {
+            fs::create_dir(dir.path().join("src"))?;
+            fs::write(dir.path().join("src/f.txt"), content)?;
+        }
+        let (first, second) = (a.path().join("src"), b.path().join("src"));
+        let sources = [first.as_path(), second.as_path(), first.as_path()];
+        let (snapshot, summary) = backup(&repo, &sources, Vec::new())?;
+        assert_eq!(summary.files, 2);
+        assert_eq!(snapshot.paths.len(), 2);
+
+        let root = load_tree(&repo, &snapshot.tree)?;
+        let names: Vec<_> = root.nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, ["src", "src (2)"]);
+        let restored = tempdir()?;
+        crate::restore::restore(&repo, &snapshot.id, restored.path(), &Default::default())?;
+        assert_eq!(fs::read(restored.path().join("src/f.txt"))?, b"first");
+        assert_eq!(fs::read(restored.path().join("src (2)/f.txt"))?, b"second");
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_backup_writes_nothing_after_unlocking() -> io::Result<()> {
+        let src = tempdir()?;
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        fs::write(src.path().join("a.txt"), b"buffered")?;
+        let missing = src.path().join("missing");
+        assert!(backup(&repo, &[src.path(), &missing], Vec::new()).is_err());
+        assert!(repo.list_blobs()?.is_empty());
+        drop(repo);
+        assert!(!temp.path().join("packs").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_removed_by_others_fails_the_backup() -> io::Result<()> {
+        let src = tempdir()?;
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        fs::write(src.path().join("a.txt"), b"data")?;
+        let _lock = repo.lock_shared()?;
+        repo.backdate_locks(crate::lock::REFRESH_AFTER)?;
+        let other = Repository::open(temp.path(), "pw")?;
+        assert_eq!(other.remove_locks(true)?.len(), 1);
+        let mut backup = Backup { repo: &repo, summary: BackupSummary::default() };
+        assert!(backup.file(&src.path().join("a.txt")).is_err());
+        assert!(repo.list_locks()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_refreshes_its_lock() -> io::Result<()> {
+        let src = tempdir()?;
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        fs::write(src.path().join("a.txt"), b"already stored")?;
+        backup(&repo, &[src.path()], Vec::new())?;
+
+        let _lock = repo.lock_shared()?;
+        repo.backdate_locks(STALE_AFTER + chrono::Duration::minutes(1))?;
+        assert!(repo.list_locks()?[0].is_stale());
+        let mut backup = Backup { repo: &repo, summary: BackupSummary::default() };
+        backup.file(&src.path().join("a.txt"))?;
+        assert_eq!(backup.summary.new_chunks, 0);
+        assert!(!repo.list_locks()?[0].is_stale());
+        Ok(())
+    }
+}
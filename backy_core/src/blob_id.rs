@@ -0,0 +1,85 @@
+// Blob identifiers: keyed BLAKE3 hash of the plaintext contents
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Content address of a blob.
+/// Computed with a repository-specific key so IDs leak nothing about plaintext.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlobId([u8; 32]);
+
+impl BlobId {
+    /// Compute the ID of `data` under the repository's ID key.
+    pub fn compute(id_key: &[u8; 32], data: &[u8]) -> Self {
+        BlobId(*blake3::keyed_hash(id_key, data).as_bytes())
+    }
+
+    /// Raw bytes of the ID.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for BlobId {
+    fn from(bytes: [u8; 32]) -> Self {
+        BlobId(bytes)
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlobId({})", self)
+    }
+}
+
+impl FromStr for BlobId {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(BlobId(bytes))
+    }
+}
+
+impl Serialize for BlobId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for BlobId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_content_same_id() {
+        let key = [7u8; 32];
+        assert_eq!(BlobId::compute(&key, b"abc"), BlobId::compute(&key, b"abc"));
+        assert_ne!(BlobId::compute(&key, b"abc"), BlobId::compute(&key, b"abd"));
+        assert_ne!(BlobId::compute(&key, b"abc"), BlobId::compute(&[8u8; 32], b"abc"));
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        let id = BlobId::compute(&[1u8; 32], b"data");
+        let parsed: BlobId = id.to_string().parse().unwrap();
+        assert_eq!(parsed, id);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(serde_json::from_str::<BlobId>(&json).unwrap(), id);
+    }
+}
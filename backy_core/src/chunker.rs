@@ -1,24 +1,80 @@
 // chunker module using FastCDC for content-defined chunking
 
-use fastcdc::ronomon::FastCDC;
+use fastcdc::v2020::StreamCDC;
 use std::fs::File;
 use std::io::{self, Read};
+use std::path::Path;
+
+// chunk size parameters: min 1 MiB, avg 4 MiB, max 8 MiB
+const MIN_SIZE: u32 = 1 << 20;
+const AVG_SIZE: u32 = 1 << 22;
+const MAX_SIZE: u32 = 1 << 23;
+
+/// A single content-defined chunk read from a source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// Byte offset of the chunk within the source.
+    pub offset: u64,
+    /// Length of the chunk in bytes.
+    pub length: usize,
+    /// The chunk contents.
+    pub data: Vec<u8>,
+}
+
+/// Streaming chunk iterator over any `Read` source.
+/// Memory use is bounded by the maximum chunk size.
+pub struct ChunkIter<R: Read> {
+    inner: StreamCDC<R>,
+}
+
+impl<R: Read> Iterator for ChunkIter<R> {
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|res| {
+            res.map(|c| Chunk { offset: c.offset, length: c.length, data: c.data })
+                .map_err(io::Error::from)
+        })
+    }
+}
+
+/// Chunk a reader using Content-Defined Chunking (FastCDC streaming mode).
+pub fn chunk_reader<R: Read>(reader: R) -> ChunkIter<R> {
+    ChunkIter { inner: StreamCDC::new(reader, MIN_SIZE, AVG_SIZE, MAX_SIZE) }
+}
 
 /// Chunk a file at the given path using Content-Defined Chunking (FastCDC).
-/// Returns a vector of byte vectors, each representing a chunk.
-pub fn chunk_file(path: &str) -> io::Result<Vec<Vec<u8>>> {
-    let mut file = File::open(path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    // chunk size parameters: min 2 MiB, avg 4 MiB, max 8 MiB
-    let min_size = 1 << 21;
-    let avg_size = 1 << 22;
-    let max_size = 1 << 23;
-    let mut chunks = Vec::new();
-    for sec in FastCDC::new(&buffer, min_size, avg_size, max_size) {
-        let start = sec.offset as usize;
-        let end = start + sec.length as usize;
-        chunks.push(buffer[start..end].to_vec());
+/// Returns an iterator yielding each chunk with its offset and length.
+pub fn chunk_file<P: AsRef<Path>>(path: P) -> io::Result<ChunkIter<File>> {
+    Ok(chunk_reader(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+    use std::io::Cursor;
+
+    #[test]
+    fn chunks_cover_source() -> io::Result<()> {
+        let mut data = vec![0u8; 20 * 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut data);
+        let mut expected_offset = 0u64;
+        let mut rebuilt = Vec::new();
+        for chunk in chunk_reader(Cursor::new(&data)) {
+            let chunk = chunk?;
+            assert_eq!(chunk.offset, expected_offset);
+            assert_eq!(chunk.length, chunk.data.len());
+            assert!(chunk.length <= MAX_SIZE as usize);
+            expected_offset += chunk.length as u64;
+            rebuilt.extend_from_slice(&chunk.data);
+        }
+        assert_eq!(rebuilt, data);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_source_has_no_chunks() {
+        assert_eq!(chunk_reader(Cursor::new(Vec::new())).count(), 0);
     }
-    Ok(chunks)
 }
@@ -0,0 +1,112 @@
+// Compression module: optional zstd compression of blobs before encryption
+
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Size of the prefix compressed by `Auto` to decide whether a blob is worth
+/// compressing at all.
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Compression algorithm recorded in a blob envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// How blobs are compressed when saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", content = "level", rename_all = "lowercase")]
+pub enum CompressionMode {
+    /// Store blobs as-is.
+    Off,
+    /// Always compress with zstd at the given level.
+    Zstd(i32),
+    /// Compress with zstd at the given level, but store data that does not
+    /// shrink (media, archives, already-compressed dumps) uncompressed.
+    Auto(i32),
+}
+
+impl Default for CompressionMode {
+    fn default() -> Self {
+        CompressionMode::Auto(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+/// Compress `data` according to `mode`.
+/// Returns the algorithm actually applied along with the output.
+pub fn compress(data: &[u8], mode: CompressionMode) -> io::Result<(Compression, Vec<u8>)> {
+    match mode {
+        CompressionMode::Off => Ok((Compression::None, data.to_vec())),
+        CompressionMode::Zstd(level) => Ok((Compression::Zstd, zstd::bulk::compress(data, level)?)),
+        CompressionMode::Auto(level) => {
+            let sample = &data[..data.len().min(SAMPLE_SIZE)];
+            let sample_out = zstd::bulk::compress(sample, 1)?;
+            // Less than ~3% saved on the sample: treat as incompressible.
+            if sample_out.len() * 100 >= sample.len() * 97 {
+                return Ok((Compression::None, data.to_vec()));
+            }
+            let compressed = zstd::bulk::compress(data, level)?;
+            if compressed.len() < data.len() {
+                Ok((Compression::Zstd, compressed))
+            } else {
+                Ok((Compression::None, data.to_vec()))
+            }
+        }
+    }
+}
+
+/// Undo `compress`.
+pub fn decompress(data: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zstd => zstd::stream::decode_all(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    #[test]
+    fn compressible_data_roundtrip() -> io::Result<()> {
+        let data = b"log line repeated\n".repeat(10_000);
+        for mode in [CompressionMode::Zstd(3), CompressionMode::Auto(3)] {
+            let (algo, out) = compress(&data, mode)?;
+            assert_eq!(algo, Compression::Zstd);
+            assert!(out.len() < data.len() / 10);
+            assert_eq!(decompress(&out, algo)?, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn auto_skips_incompressible_data() -> io::Result<()> {
+        let mut data = vec![0u8; 256 * 1024];
+        rand::thread_rng().fill_bytes(&mut data);
+        let (algo, out) = compress(&data, CompressionMode::Auto(3))?;
+        assert_eq!(algo, Compression::None);
+        assert_eq!(out, data);
+        let (algo, _) = compress(&data, CompressionMode::Off)?;
+        assert_eq!(algo, Compression::None);
+        Ok(())
+    }
+}
@@ -1,29 +1,58 @@
 // Crypto module: AES-256-GCM encryption + Argon2 key derivation
 
-use aes_gcm::{Aes256Gcm, KeyInit, aead::{Aead, OsRng, generic_array::GenericArray}};
+use crate::compression::{CompressionMode, compress, decompress};
+use crate::envelope::{Algorithm, Envelope, KEY_ID_LEN, VERSION};
+use aes_gcm::{Aes256Gcm, KeyInit, aead::{Aead, OsRng, Payload, generic_array::GenericArray}};
 use rand::RngCore;
 use argon2::Argon2;
 use password_hash::SaltString;
 
-/// Encrypt data with password:
-/// - derive 32-byte key via Argon2id
-/// - generate random 12-byte nonce
-/// - return (salt, nonce, ciphertext)
+/// Derive a 32-byte key from a password and salt via Argon2id.
 /// Errors are returned as String.
-pub fn encrypt(data: &[u8], password: &str) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
-    let salt = SaltString::generate(&mut OsRng);
-    let salt_bytes = salt.as_bytes().to_vec();
+pub fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], String> {
     let mut key = [0u8; 32];
     Argon2::default()
-        .hash_password_into(password.as_bytes(), salt.as_bytes(), &mut key)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
         .map_err(|e| e.to_string())?;
-    let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+    Ok(key)
+}
+
+/// Encrypt data with a 32-byte key and a fresh random 12-byte nonce.
+/// Returns (nonce, ciphertext).
+pub fn encrypt_with_key(key: &[u8; 32], data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
     let mut nonce = [0u8; 12];
     OsRng.fill_bytes(&mut nonce);
     let ciphertext = cipher
         .encrypt(GenericArray::from_slice(&nonce), data)
         .map_err(|e| e.to_string())?;
-    Ok((salt_bytes, nonce.to_vec(), ciphertext))
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+/// Decrypt data with a 32-byte key and the nonce used to encrypt it.
+pub fn decrypt_with_key(key: &[u8; 32], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    if nonce.len() != 12 {
+        return Err("invalid nonce length".to_string());
+    }
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+    cipher
+        .decrypt(GenericArray::from_slice(nonce), ciphertext)
+        .map_err(|e| e.to_string())
+}
+
+/// Encrypt data with password:
+/// - derive 32-byte key via Argon2id
+/// - generate random 12-byte nonce
+/// - return (salt, nonce, ciphertext)
+///
+/// Errors are returned as String.
+#[allow(clippy::type_complexity)]
+pub fn encrypt(data: &[u8], password: &str) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let salt_bytes = salt.as_bytes().to_vec();
+    let key = derive_key(password, &salt_bytes)?;
+    let (nonce, ciphertext) = encrypt_with_key(&key, data)?;
+    Ok((salt_bytes, nonce, ciphertext))
 }
 
 /// Decrypt data with password, salt, nonce.
@@ -34,15 +63,61 @@ pub fn decrypt(
     ciphertext: &[u8],
     password: &str,
 ) -> Result<Vec<u8>, String> {
-    let mut key = [0u8; 32];
-    Argon2::default()
-        .hash_password_into(password.as_bytes(), salt, &mut key)
+    let key = derive_key(password, salt)?;
+    decrypt_with_key(&key, nonce, ciphertext)
+}
+
+/// Compress data according to `mode`, then encrypt it into a self-describing
+/// envelope (see `envelope`) with a data key identified by `key_id`.
+/// The envelope header, including the compression used, is authenticated.
+pub fn encrypt_blob(
+    data: &[u8],
+    key: &[u8; 32],
+    key_id: [u8; KEY_ID_LEN],
+    mode: CompressionMode,
+) -> Result<Vec<u8>, String> {
+    let (compression, data) = compress(data, mode).map_err(|e| e.to_string())?;
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let mut envelope = Envelope {
+        version: VERSION,
+        algorithm: Algorithm::Aes256Gcm,
+        compression,
+        key_id,
+        nonce: nonce.to_vec(),
+        ciphertext: Vec::new(),
+    };
+    let header = envelope.header();
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+    envelope.ciphertext = cipher
+        .encrypt(GenericArray::from_slice(&nonce), Payload { msg: &data, aad: &header })
         .map_err(|e| e.to_string())?;
-    let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+    Ok(envelope.to_bytes())
+}
+
+/// Decrypt and decompress an envelope produced by `encrypt_blob`.
+/// Fails if the envelope was written with a different key ID.
+pub fn decrypt_blob(
+    blob: &[u8],
+    key: &[u8; 32],
+    key_id: [u8; KEY_ID_LEN],
+) -> Result<Vec<u8>, String> {
+    let envelope = Envelope::parse(blob).map_err(|e| e.to_string())?;
+    if envelope.key_id != key_id {
+        return Err("blob was encrypted with a different key".to_string());
+    }
+    if envelope.nonce.len() != 12 {
+        return Err("invalid nonce length".to_string());
+    }
+    let header = envelope.header();
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
     let plaintext = cipher
-        .decrypt(GenericArray::from_slice(nonce), ciphertext)
+        .decrypt(
+            GenericArray::from_slice(&envelope.nonce),
+            Payload { msg: &envelope.ciphertext, aad: &header },
+        )
         .map_err(|e| e.to_string())?;
-    Ok(plaintext)
+    decompress(&plaintext, envelope.compression).map_err(|e| e.to_string())
 }
 
 #[cfg(test)]
@@ -57,4 +132,38 @@ mod tests {
         let pt = decrypt(&salt, &nonce, &ct, password).expect("decrypt failed");
         assert_eq!(pt, data);
     }
+
+    #[test]
+    fn blob_roundtrip() {
+        let key = [9u8; 32];
+        let key_id = [2u8; KEY_ID_LEN];
+        let blob = encrypt_blob(b"chunk data", &key, key_id, CompressionMode::Off)
+            .expect("encrypt failed");
+        assert_eq!(decrypt_blob(&blob, &key, key_id).expect("decrypt failed"), b"chunk data");
+        assert!(decrypt_blob(&blob, &[1u8; 32], key_id).is_err());
+        assert!(decrypt_blob(&blob, &key, [3u8; KEY_ID_LEN]).is_err());
+        assert!(decrypt_blob(&blob[..10], &key, key_id).is_err());
+    }
+
+    #[test]
+    fn blob_header_is_authenticated() {
+        let key = [9u8; 32];
+        let key_id = [2u8; KEY_ID_LEN];
+        let mut blob = encrypt_blob(b"chunk data", &key, key_id, CompressionMode::Off)
+            .expect("encrypt failed");
+        // Flip the compression byte, which is part of the header.
+        blob[6] ^= 1;
+        assert!(decrypt_blob(&blob, &key, key_id).is_err());
+    }
+
+    #[test]
+    fn blob_compressed_roundtrip() {
+        let key = [9u8; 32];
+        let key_id = [2u8; KEY_ID_LEN];
+        let data = b"abcabcabc".repeat(1000);
+        let blob = encrypt_blob(&data, &key, key_id, CompressionMode::default())
+            .expect("encrypt failed");
+        assert!(blob.len() < data.len() / 10);
+        assert_eq!(decrypt_blob(&blob, &key, key_id).expect("decrypt failed"), data);
+    }
 }
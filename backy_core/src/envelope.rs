@@ -0,0 +1,197 @@
+// Envelope module: self-describing binary container for encrypted blobs
+//
+// Layout of a version 2 envelope (all integers are single bytes):
+//
+//   offset  size  field
+//   0       4     magic, "BKYB"
+//   4       1     format version, currently 2
+//   5       1     algorithm ID (1 = AES-256-GCM)
+//   6       1     compression ID of the plaintext (0 = none, 1 = zstd)
+//   7       16    key ID of the data key the blob was encrypted with
+//   23      1     nonce length N
+//   24      N     nonce
+//   24+N    ..    ciphertext followed by the authentication tag
+//
+// Version 1 is identical minus the compression byte and implies no
+// compression; it is still accepted by `Envelope::parse`.
+//
+// Everything before the ciphertext is the header. It is passed to the cipher
+// as associated data, so tampering with it makes decryption fail. Readers
+// must reject unknown versions and algorithms rather than guess.
+
+use crate::compression::Compression;
+use thiserror::Error;
+
+/// Magic bytes at the start of every envelope.
+pub const MAGIC: [u8; 4] = *b"BKYB";
+
+/// Envelope format version written by this build.
+pub const VERSION: u8 = 2;
+
+/// Length of the key ID field.
+pub const KEY_ID_LEN: usize = 16;
+
+/// Encryption algorithm of an envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Aes256Gcm,
+}
+
+impl Algorithm {
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::Aes256Gcm => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Algorithm::Aes256Gcm),
+            _ => None,
+        }
+    }
+}
+
+/// Errors returned when parsing an envelope.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EnvelopeError {
+    #[error("envelope is truncated")]
+    Truncated,
+    #[error("bad magic, not a backy blob")]
+    BadMagic,
+    #[error("unsupported envelope version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unsupported algorithm {0}")]
+    UnsupportedAlgorithm(u8),
+    #[error("unsupported compression {0}")]
+    UnsupportedCompression(u8),
+}
+
+/// A parsed envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub version: u8,
+    pub algorithm: Algorithm,
+    pub compression: Compression,
+    pub key_id: [u8; KEY_ID_LEN],
+    pub nonce: Vec<u8>,
+    /// Ciphertext including the authentication tag.
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    /// Serialized header: everything before the ciphertext.
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(24 + self.nonce.len());
+        header.extend_from_slice(&MAGIC);
+        header.push(self.version);
+        header.push(self.algorithm.id());
+        if self.version >= 2 {
+            header.push(self.compression.id());
+        }
+        header.extend_from_slice(&self.key_id);
+        header.push(self.nonce.len() as u8);
+        header.extend_from_slice(&self.nonce);
+        header
+    }
+
+    /// Serialize the envelope to bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header();
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    /// Parse an envelope from bytes.
+    pub fn parse(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        if bytes.len() < 6 {
+            return Err(EnvelopeError::Truncated);
+        }
+        if bytes[0..4] != MAGIC {
+            return Err(EnvelopeError::BadMagic);
+        }
+        let version = bytes[4];
+        let algorithm = Algorithm::from_id(bytes[5])
+            .ok_or(EnvelopeError::UnsupportedAlgorithm(bytes[5]))?;
+        let (compression, rest) = match version {
+            1 => (Compression::None, &bytes[6..]),
+            2 => {
+                let id = *bytes.get(6).ok_or(EnvelopeError::Truncated)?;
+                let compression = Compression::from_id(id)
+                    .ok_or(EnvelopeError::UnsupportedCompression(id))?;
+                (compression, &bytes[7..])
+            }
+            _ => return Err(EnvelopeError::UnsupportedVersion(version)),
+        };
+        if rest.len() < KEY_ID_LEN + 1 {
+            return Err(EnvelopeError::Truncated);
+        }
+        let mut key_id = [0u8; KEY_ID_LEN];
+        key_id.copy_from_slice(&rest[..KEY_ID_LEN]);
+        let nonce_len = rest[KEY_ID_LEN] as usize;
+        let rest = &rest[KEY_ID_LEN + 1..];
+        if rest.len() < nonce_len {
+            return Err(EnvelopeError::Truncated);
+        }
+        let (nonce, ciphertext) = rest.split_at(nonce_len);
+        Ok(Envelope {
+            version,
+            algorithm,
+            compression,
+            key_id,
+            nonce: nonce.to_vec(),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Envelope {
+        Envelope {
+            version: VERSION,
+            algorithm: Algorithm::Aes256Gcm,
+            compression: Compression::Zstd,
+            key_id: [5u8; KEY_ID_LEN],
+            nonce: vec![1u8; 12],
+            ciphertext: b"ciphertext and tag".to_vec(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let env = sample();
+        let bytes = env.to_bytes();
+        assert_eq!(&bytes[0..4], b"BKYB");
+        assert_eq!(Envelope::parse(&bytes), Ok(env));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let bytes = sample().to_bytes();
+        assert_eq!(Envelope::parse(&bytes[..10]), Err(EnvelopeError::Truncated));
+        assert_eq!(Envelope::parse(&bytes[..30]), Err(EnvelopeError::Truncated));
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(Envelope::parse(&bad), Err(EnvelopeError::BadMagic));
+        let mut bad = bytes.clone();
+        bad[4] = 99;
+        assert_eq!(Envelope::parse(&bad), Err(EnvelopeError::UnsupportedVersion(99)));
+        let mut bad = bytes.clone();
+        bad[5] = 7;
+        assert_eq!(Envelope::parse(&bad), Err(EnvelopeError::UnsupportedAlgorithm(7)));
+        let mut bad = bytes;
+        bad[6] = 9;
+        assert_eq!(Envelope::parse(&bad), Err(EnvelopeError::UnsupportedCompression(9)));
+    }
+
+    #[test]
+    fn parses_version_1() {
+        let v1 = Envelope { version: 1, compression: Compression::None, ..sample() };
+        let bytes = v1.to_bytes();
+        assert_eq!(bytes.len(), 23 + 12 + v1.ciphertext.len());
+        assert_eq!(Envelope::parse(&bytes), Ok(v1));
+    }
+}
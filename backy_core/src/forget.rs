@@ -0,0 +1,370 @@
+// Forget module: apply retention policies to the snapshot list
+//
+// Snapshots are grouped by host and source paths, and every group is
+// evaluated on its own, newest snapshot first. A snapshot is kept if any
+// rule of the policy selects it; the reasons are reported so the outcome can
+// be reviewed before anything is removed. Forgetting only deletes snapshot
+// manifests; run `prune` afterwards to reclaim the space of their data.
+
+use crate::repository::Repository;
+use crate::snapshot::{Snapshot, list_snapshots, remove_snapshot};
+use chrono::{Datelike, Duration, Local, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+
+/// Which snapshots to keep. Counts of 0 disable a rule.
+///
+/// The periodic rules keep the newest snapshot of each of the last N hours,
+/// days, ... that have snapshots, in local time.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RetentionPolicy {
+    /// Keep the N newest snapshots.
+    pub keep_last: u32,
+    pub keep_hourly: u32,
+    pub keep_daily: u32,
+    pub keep_weekly: u32,
+    pub keep_monthly: u32,
+    pub keep_yearly: u32,
+    /// Keep snapshots carrying any of these tags.
+    pub keep_tags: Vec<String>,
+    /// Keep snapshots taken within this duration of the newest snapshot of
+    /// their group. Written like `1y6m`, `2w` or `36h`.
+    #[serde(deserialize_with = "deserialize_keep_within")]
+    pub keep_within: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// Whether the policy has no rule at all, which would forget every
+    /// snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_hourly == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_yearly == 0
+            && self.keep_tags.is_empty()
+            && self.keep_within.is_none()
+    }
+}
+
+/// Parse a duration such as `1y6m`, `2w3d` or `36h`. Years count as 365
+/// days and months as 30. Durations too long to represent are rejected.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let too_long = || format!("duration '{}' is too long", s);
+    let mut total = Duration::zero();
+    let mut digits = String::new();
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let n: i64 = digits.parse().map_err(|_| format!("invalid duration '{}'", s))?;
+        digits.clear();
+        let part = match c {
+            'y' => n.checked_mul(365).and_then(Duration::try_days),
+            'm' => n.checked_mul(30).and_then(Duration::try_days),
+            'w' => Duration::try_weeks(n),
+            'd' => Duration::try_days(n),
+            'h' => Duration::try_hours(n),
+            _ => return Err(format!("invalid duration unit '{}' in '{}'", c, s)),
+        };
+        total = part.and_then(|part| total.checked_add(&part)).ok_or_else(too_long)?;
+    }
+    if !digits.is_empty() || total.is_zero() {
+        return Err(format!("invalid duration '{}'", s));
+    }
+    Ok(total)
+}
+
+fn deserialize_keep_within<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    match Option::<String>::deserialize(d)? {
+        Some(s) if !s.trim().is_empty() => {
+            parse_duration(&s).map(Some).map_err(serde::de::Error::custom)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The outcome for one snapshot.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotDecision {
+    pub snapshot: Snapshot,
+    /// Rules that keep the snapshot. Empty if it is forgotten.
+    pub reasons: Vec<String>,
+}
+
+impl SnapshotDecision {
+    pub fn keep(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+}
+
+/// Snapshots of one host and set of source paths, newest first.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForgetGroup {
+    pub hostname: String,
+    pub paths: Vec<String>,
+    pub snapshots: Vec<SnapshotDecision>,
+}
+
+/// A periodic rule: its name, how many periods to keep and the period a
+/// local time falls in.
+type PeriodRule = (&'static str, u32, fn(NaiveDateTime) -> String);
+
+fn period_rules(policy: &RetentionPolicy) -> [PeriodRule; 5] {
+    [
+        ("hourly", policy.keep_hourly, |t| t.format("%Y-%m-%d %H").to_string()),
+        ("daily", policy.keep_daily, |t| t.format("%Y-%m-%d").to_string()),
+        ("weekly", policy.keep_weekly, |t| {
+            let week = t.iso_week();
+            format!("{}-W{:02}", week.year(), week.week())
+        }),
+        ("monthly", policy.keep_monthly, |t| t.format("%Y-%m").to_string()),
+        ("yearly", policy.keep_yearly, |t| t.format("%Y").to_string()),
+    ]
+}
+
+/// Decide for one group of snapshots, sorted newest first, with periods in
+/// time zone `tz`.
+fn evaluate<Tz: TimeZone>(
+    snapshots: &[Snapshot],
+    policy: &RetentionPolicy,
+    tz: &Tz,
+) -> Vec<Vec<String>> {
+    let mut reasons = vec![Vec::new(); snapshots.len()];
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        if (i as u64) < u64::from(policy.keep_last) {
+            reasons[i].push("last snapshot".to_string());
+        }
+        for tag in snapshot.tags.iter().filter(|t| policy.keep_tags.contains(t)) {
+            reasons[i].push(format!("tagged '{}'", tag));
+        }
+    }
+    if let (Some(within), Some(newest)) = (policy.keep_within, snapshots.first()) {
+        // A period reaching back before the earliest representable time
+        // covers every snapshot.
+        let since = newest.time.checked_sub_signed(within);
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            if since.is_none_or(|since| snapshot.time >= since) {
+                reasons[i].push("within keep-within period".to_string());
+            }
+        }
+    }
+    for (name, count, period) in period_rules(policy) {
+        let mut remaining = count;
+        let mut last = None;
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+            let current = period(snapshot.time.with_timezone(tz).naive_local());
+            if last.as_ref() != Some(&current) {
+                reasons[i].push(format!("{} snapshot", name));
+                remaining -= 1;
+                last = Some(current);
+            }
+        }
+    }
+    reasons
+}
+
+/// Evaluate `policy` against `snapshots`, grouped by host and source paths.
+pub fn apply_policy(snapshots: Vec<Snapshot>, policy: &RetentionPolicy) -> Vec<ForgetGroup> {
+    apply_policy_in(snapshots, policy, &Local)
+}
+
+/// `apply_policy` with periods in time zone `tz` instead of local time.
+fn apply_policy_in<Tz: TimeZone>(
+    snapshots: Vec<Snapshot>,
+    policy: &RetentionPolicy,
+    tz: &Tz,
+) -> Vec<ForgetGroup> {
+    let mut groups: BTreeMap<(String, Vec<String>), Vec<Snapshot>> = BTreeMap::new();
+    for snapshot in snapshots {
+        let mut paths = snapshot.paths.clone();
+        paths.sort();
+        groups.entry((snapshot.hostname.clone(), paths)).or_default().push(snapshot);
+    }
+    groups
+        .into_iter()
+        .map(|((hostname, paths), mut snapshots)| {
+            snapshots.sort_by_key(|s| std::cmp::Reverse(s.time));
+            let reasons = evaluate(&snapshots, policy, tz);
+            let snapshots = snapshots
+                .into_iter()
+                .zip(reasons)
+                .map(|(snapshot, reasons)| SnapshotDecision { snapshot, reasons })
+                .collect();
+            ForgetGroup { hostname, paths, snapshots }
+        })
+        .collect()
+}
+
+/// Apply `policy` to the snapshots of the repository and, unless `dry_run`
+/// is set, remove those it does not keep. Refuses an empty policy. Holds an
+/// exclusive lock on the repository, or a shared one for a dry run.
+pub fn forget(
+    repo: &Repository,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> io::Result<Vec<ForgetGroup>> {
+    if policy.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "retention policy has no rule and would forget every snapshot",
+        ));
+    }
+    let _lock = if dry_run { repo.lock_shared()? } else { repo.lock_exclusive()? };
+    let groups = apply_policy(list_snapshots(repo)?, policy);
+    if !dry_run {
+        for decision in groups.iter().flat_map(|g| &g.snapshots).filter(|d| !d.keep()) {
+            remove_snapshot(repo, &decision.snapshot.id)?;
+        }
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_id::BlobId;
+    use crate::snapshot::save_snapshot;
+    use chrono::{DateTime, FixedOffset, Utc};
+    use tempfile::tempdir;
+
+    fn snapshot(host: &str, path: &str, time: DateTime<Utc>, tags: &[&str]) -> Snapshot {
+        let mut s = Snapshot::new(
+            vec![path.to_string()],
+            tags.iter().map(|t| t.to_string()).collect(),
+            BlobId::from([0u8; 32]),
+        );
+        s.hostname = host.to_string();
+        s.time = time;
+        s
+    }
+
+    /// Time `days` days and `hours` hours before `now`.
+    fn ago(now: DateTime<Utc>, days: i64, hours: i64) -> DateTime<Utc> {
+        now - Duration::days(days) - Duration::hours(hours)
+    }
+
+    fn kept(group: &ForgetGroup) -> Vec<DateTime<Utc>> {
+        group.snapshots.iter().filter(|d| d.keep()).map(|d| d.snapshot.time).collect()
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("1y6m"), Ok(Duration::days(365 + 180)));
+        assert_eq!(parse_duration("2w3d"), Ok(Duration::days(17)));
+        assert_eq!(parse_duration("36h"), Ok(Duration::hours(36)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("3").is_err());
+        assert!(parse_duration("3x").is_err());
+        assert!(parse_duration("100000000000y").is_err());
+        assert!(parse_duration("9223372036854775807h").is_err());
+        assert!(parse_duration("99999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_keep_last_daily_and_tags() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 15, 12, 0, 0).unwrap();
+        // Two snapshots a day for ten days, plus one tagged snapshot long ago.
+        let mut snapshots: Vec<Snapshot> = (0..10)
+            .flat_map(|d| [ago(now, d, 0), ago(now, d, 1)])
+            .map(|t| snapshot("pc", "/home", t, &[]))
+            .collect();
+        snapshots.push(snapshot("pc", "/home", ago(now, 400, 0), &["release"]));
+        let policy = RetentionPolicy {
+            keep_last: 3,
+            keep_daily: 4,
+            keep_tags: vec!["release".into()],
+            ..Default::default()
+        };
+        let groups = apply_policy_in(snapshots.clone(), &policy, &Utc);
+        assert_eq!(groups.len(), 1);
+        let expected =
+            [(0, 0), (0, 1), (1, 0), (2, 0), (3, 0), (400, 0)].map(|(d, h)| ago(now, d, h));
+        assert_eq!(kept(&groups[0]), expected);
+
+        // At UTC+12 the snapshots taken at 12:00 UTC fall just after
+        // midnight of the next day, so apart from the newest day the
+        // 11:00 UTC snapshots are the newest of their day.
+        let auckland = FixedOffset::east_opt(12 * 3600).unwrap();
+        let groups = apply_policy_in(snapshots, &policy, &auckland);
+        let expected =
+            [(0, 0), (0, 1), (1, 0), (1, 1), (2, 1), (400, 0)].map(|(d, h)| ago(now, d, h));
+        assert_eq!(kept(&groups[0]), expected);
+        let newest = &groups[0].snapshots[0];
+        assert_eq!(newest.reasons, ["last snapshot", "daily snapshot"]);
+        assert_eq!(groups[0].snapshots.last().unwrap().reasons, ["tagged 'release'"]);
+    }
+
+    #[test]
+    fn test_groups_and_keep_within() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 15, 12, 0, 0).unwrap();
+        let snapshots = vec![
+            snapshot("pc", "/home", ago(now, 0, 0), &[]),
+            snapshot("pc", "/home", ago(now, 5, 0), &[]),
+            snapshot("pc", "/home", ago(now, 9, 0), &[]),
+            // The newest snapshot of this group is old; keep-within is
+            // relative to it, not to the current time.
+            snapshot("laptop", "/home", ago(now, 100, 0), &[]),
+            snapshot("laptop", "/home", ago(now, 106, 0), &[]),
+        ];
+        let policy =
+            RetentionPolicy { keep_within: Some(Duration::weeks(1)), ..Default::default() };
+        let groups = apply_policy(snapshots.clone(), &policy);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].hostname, "laptop");
+        assert_eq!(kept(&groups[0]), [ago(now, 100, 0), ago(now, 106, 0)]);
+        assert_eq!(kept(&groups[1]), [ago(now, 0, 0), ago(now, 5, 0)]);
+
+        let policy = RetentionPolicy {
+            keep_within: Some(parse_duration("1000000y").unwrap()),
+            ..Default::default()
+        };
+        // Reaches back before the earliest representable time.
+        let groups = apply_policy(snapshots, &policy);
+        assert!(groups.iter().flat_map(|g| &g.snapshots).all(|d| d.keep()));
+    }
+
+    #[test]
+    fn test_monthly_and_yearly() {
+        let months: Vec<DateTime<Utc>> = (1..=12)
+            .flat_map(|m| [2022, 2023].map(|y| Utc.with_ymd_and_hms(y, m, 15, 12, 0, 0).unwrap()))
+            .collect();
+        let snapshots = months.iter().map(|&t| snapshot("pc", "/", t, &[])).collect();
+        let policy = RetentionPolicy { keep_monthly: 3, keep_yearly: 2, ..Default::default() };
+        let groups = apply_policy(snapshots, &policy);
+        let at = |y, m| Utc.with_ymd_and_hms(y, m, 15, 12, 0, 0).unwrap();
+        assert_eq!(kept(&groups[0]), [at(2023, 12), at(2023, 11), at(2023, 10), at(2022, 12)]);
+    }
+
+    #[test]
+    fn test_forget_removes_snapshots() -> io::Result<()> {
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        let now = Utc::now();
+        for d in 0..5 {
+            save_snapshot(&repo, &snapshot("pc", "/home", ago(now, d, 0), &[]))?;
+        }
+        assert!(forget(&repo, &RetentionPolicy::default(), false).is_err());
+        let policy = RetentionPolicy { keep_last: 2, ..Default::default() };
+        forget(&repo, &policy, true)?;
+        assert_eq!(list_snapshots(&repo)?.len(), 5);
+        let groups = forget(&repo, &policy, false)?;
+        assert_eq!(groups[0].snapshots.iter().filter(|d| !d.keep()).count(), 3);
+        assert_eq!(list_snapshots(&repo)?.len(), 2);
+
+        let policy: RetentionPolicy =
+            serde_json::from_str(r#"{"keepDaily": 7, "keepWithin": "2w", "keepTags": ["x"]}"#)?;
+        assert_eq!(policy.keep_within, Some(Duration::weeks(2)));
+        assert!(serde_json::from_str::<RetentionPolicy>(r#"{"keepWithin": "2q"}"#).is_err());
+        Ok(())
+    }
+}
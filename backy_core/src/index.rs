@@ -0,0 +1,246 @@
+// Index module: locate blobs inside packs
+//
+// The index is a set of segments under `index/`, each a JSON list of the
+// blobs in the packs written together, named so that they sort in the order
+// they were written. Segments are only ever added, each in a single atomic
+// write, so updating the index costs the size of the new entries and an
+// interrupted write loses at most the segment in flight; the packs it
+// described stay in the repository for `rebuild_index` to recover. Once
+// segments pile up they are merged into one, writing the merged segment
+// before deleting those it replaces.
+
+use crate::backend::Backend;
+use crate::blob_id::BlobId;
+use crate::pack::PackEntry;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::{io, iter};
+use uuid::Uuid;
+
+/// Prefix of the index segments.
+const INDEX_DIR: &str = "index";
+
+/// Number of segments above which they are merged into one.
+pub(crate) const CONSOLIDATE_AFTER: usize = 32;
+
+/// A single entry in the repository index: where a blob lives.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct IndexEntry {
+    pub(crate) id: BlobId,
+    /// Name of the pack holding the blob.
+    pub(crate) pack: String,
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+impl IndexEntry {
+    pub(crate) fn new(pack: &str, entry: PackEntry) -> Self {
+        IndexEntry { id: entry.id, pack: pack.to_string(), offset: entry.offset, length: entry.length }
+    }
+}
+
+/// Keys of all segments in the backend.
+fn list_segments(backend: &dyn Backend) -> io::Result<HashSet<String>> {
+    Ok(backend.list(INDEX_DIR)?.into_iter().filter(|k| k.ends_with(".json")).collect())
+}
+
+/// The index entries of the segments loaded so far.
+#[derive(Default)]
+pub(crate) struct Index {
+    entries: Vec<IndexEntry>,
+    /// Position in `entries` of the first entry for each blob.
+    by_id: HashMap<BlobId, usize>,
+    /// Positions of further entries, for blobs stored in several packs.
+    duplicates: HashMap<BlobId, Vec<usize>>,
+    /// Keys of the loaded segments.
+    segments: HashSet<String>,
+    loaded: bool,
+}
+
+impl Index {
+    pub(crate) fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    pub(crate) fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub(crate) fn get(&self, id: &BlobId) -> Option<&IndexEntry> {
+        self.by_id.get(id).map(|&i| &self.entries[i])
+    }
+
+    pub(crate) fn contains(&self, id: &BlobId) -> bool {
+        self.by_id.contains_key(id)
+    }
+
+    pub(crate) fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Add entries in memory, skipping those already known. A blob may
+    /// still be listed in several packs when two processes saved it
+    /// concurrently.
+    pub(crate) fn add(&mut self, entries: Vec<IndexEntry>) {
+        for entry in entries {
+            let position = self.entries.len();
+            match self.by_id.get(&entry.id) {
+                None => {
+                    self.by_id.insert(entry.id, position);
+                }
+                Some(&first) => {
+                    let others = self.duplicates.get(&entry.id).into_iter().flatten();
+                    let mut known = iter::once(&first).chain(others);
+                    if known.any(|&i| self.entries[i].pack == entry.pack) {
+                        continue;
+                    }
+                    self.duplicates.entry(entry.id).or_default().push(position);
+                }
+            }
+            self.entries.push(entry);
+        }
+    }
+
+    /// Load the segments written since the last update, by this process or
+    /// others.
+    pub(crate) fn update(&mut self, backend: &dyn Backend) -> io::Result<()> {
+        loop {
+            let mut merged_away = false;
+            let mut keys: Vec<String> = list_segments(backend)?.into_iter().collect();
+            keys.sort();
+            for key in keys {
+                if self.segments.contains(&key) {
+                    continue;
+                }
+                match backend.get(&key) {
+                    Ok(data) => self.add(serde_json::from_slice(&data)?),
+                    // Merged into a new segment since listing; list again
+                    // to pick that one up.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        merged_away = true;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+                self.segments.insert(key);
+            }
+            if !merged_away {
+                self.loaded = true;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Write `entries` as a new segment.
+    pub(crate) fn append(
+        &mut self,
+        backend: &dyn Backend,
+        entries: Vec<IndexEntry>,
+    ) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let key = format!(
+            "{}/{}-{}.json",
+            INDEX_DIR,
+            Utc::now().format("%Y%m%d%H%M%S%9f"),
+            Uuid::new_v4().simple()
+        );
+        backend.put(&key, &serde_json::to_vec(&entries)?)?;
+        self.segments.insert(key);
+        self.add(entries);
+        Ok(())
+    }
+
+    /// Replace the loaded segments with a single one holding `entries`.
+    /// Segments written by others in the meantime are kept.
+    pub(crate) fn replace(
+        &mut self,
+        backend: &dyn Backend,
+        entries: Vec<IndexEntry>,
+    ) -> io::Result<()> {
+        self.replace_segments(backend, self.segments.clone(), entries)
+    }
+
+    /// Replace every segment in the backend, readable or not, with a single
+    /// one holding `entries`.
+    pub(crate) fn replace_all(
+        &mut self,
+        backend: &dyn Backend,
+        entries: Vec<IndexEntry>,
+    ) -> io::Result<()> {
+        self.replace_segments(backend, list_segments(backend)?, entries)
+    }
+
+    fn replace_segments(
+        &mut self,
+        backend: &dyn Backend,
+        old: HashSet<String>,
+        entries: Vec<IndexEntry>,
+    ) -> io::Result<()> {
+        let mut index = Index { loaded: true, ..Default::default() };
+        index.append(backend, entries)?;
+        for key in old {
+            match backend.delete(&key) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        *self = index;
+        Ok(())
+    }
+
+    /// Merge all segments into one.
+    pub(crate) fn consolidate(&mut self, backend: &dyn Backend) -> io::Result<()> {
+        self.update(backend)?;
+        let entries = self.entries.clone();
+        self.replace(backend, entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_local::LocalBackend;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn entry(byte: u8, pack: &str) -> IndexEntry {
+        IndexEntry { id: BlobId::from([byte; 32]), pack: pack.to_string(), offset: 0, length: 1 }
+    }
+
+    #[test]
+    fn test_segments_are_appended_and_consolidated() -> io::Result<()> {
+        let temp = tempdir()?;
+        let backend = LocalBackend::new(temp.path());
+        let mut index = Index::default();
+        for i in 0..5u8 {
+            index.append(&backend, vec![entry(i, "a"), entry(i + 1, "a")])?;
+        }
+        assert_eq!(index.entries().len(), 6);
+        assert_eq!(index.segment_count(), 5);
+
+        // Another process sees the segments and adds its own.
+        let mut other = Index::default();
+        other.update(&backend)?;
+        assert_eq!(other.entries().len(), 6);
+        other.append(&backend, vec![entry(0, "b")])?;
+
+        // An interrupted write leaves only a temporary file behind.
+        fs::write(temp.path().join("index").join("broken.json.tmp"), b"[{")?;
+
+        index.consolidate(&backend)?;
+        assert_eq!(index.segment_count(), 1);
+        assert_eq!(list_segments(&backend)?.len(), 1);
+        assert_eq!(index.entries().len(), 7);
+        assert_eq!(index.get(&BlobId::from([0; 32])).unwrap().pack, "a");
+
+        other.update(&backend)?;
+        assert_eq!(other.entries().len(), 7);
+        let mut fresh = Index::default();
+        fresh.update(&backend)?;
+        assert!(fresh.contains(&BlobId::from([5; 32])));
+        Ok(())
+    }
+}
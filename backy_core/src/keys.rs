@@ -0,0 +1,223 @@
+// Key module: repository master key wrapped by password-derived keys
+
+use crate::backend::Backend;
+use crate::crypto::{decrypt_with_key, derive_key, encrypt_with_key};
+use crate::envelope::KEY_ID_LEN;
+use crate::snapshot::local_hostname;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::io;
+use uuid::Uuid;
+
+/// Secret keys of a repository, generated once by `init_repo`.
+#[derive(Clone)]
+pub struct MasterKey {
+    /// Encrypts blob contents.
+    pub(crate) data_key: [u8; 32],
+    /// Keys the hash that derives blob IDs.
+    pub(crate) id_key: [u8; 32],
+}
+
+impl MasterKey {
+    /// Identifier of the data key, recorded in every blob envelope.
+    /// Derived from the key itself so it needs no storage.
+    pub fn key_id(&self) -> [u8; KEY_ID_LEN] {
+        let hash = blake3::derive_key("backy 2024 data key id", &self.data_key);
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&hash[..KEY_ID_LEN]);
+        id
+    }
+
+    /// Generate fresh random keys.
+    pub fn generate() -> Self {
+        let mut data_key = [0u8; 32];
+        let mut id_key = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut data_key);
+        rand::rngs::OsRng.fill_bytes(&mut id_key);
+        MasterKey { data_key, id_key }
+    }
+}
+
+/// On-disk key file: the master key encrypted with a key derived from a
+/// password. Argon2 only runs when a key file is written or unlocked.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    created: DateTime<Utc>,
+    hostname: String,
+    /// Argon2id salt, hex-encoded.
+    salt: String,
+    /// AES-GCM nonce, hex-encoded.
+    nonce: String,
+    /// Encrypted `KeyMaterial`, hex-encoded.
+    data: String,
+}
+
+/// Public metadata of a key slot, as shown by `Repository::list_keys`.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeySlot {
+    pub id: Uuid,
+    pub created: DateTime<Utc>,
+    pub hostname: String,
+}
+
+/// Plaintext contents of a key file.
+#[derive(Serialize, Deserialize)]
+struct KeyMaterial {
+    data_key: String,
+    id_key: String,
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn decode_key(s: &str) -> io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(s, &mut key).map_err(invalid_data)?;
+    Ok(key)
+}
+
+/// Wrap `master` with `password` and store it as a new file under `keys/`.
+/// Returns the ID of the new key file.
+pub(crate) fn add_key_file(
+    backend: &dyn Backend,
+    master: &MasterKey,
+    password: &str,
+) -> io::Result<Uuid> {
+    let mut salt = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    let wrapping_key = derive_key(password, &salt).map_err(io::Error::other)?;
+    let material = KeyMaterial {
+        data_key: hex::encode(master.data_key),
+        id_key: hex::encode(master.id_key),
+    };
+    let (nonce, data) = encrypt_with_key(&wrapping_key, &serde_json::to_vec(&material)?)
+        .map_err(io::Error::other)?;
+    let key_file = KeyFile {
+        created: Utc::now(),
+        hostname: local_hostname(),
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        data: hex::encode(data),
+    };
+    let id = Uuid::new_v4();
+    backend.put(&format!("keys/{}.json", id), &serde_json::to_vec_pretty(&key_file)?)?;
+    Ok(id)
+}
+
+/// Try to unwrap one key file with `password`.
+/// Returns `Ok(None)` if the password does not match.
+fn unwrap_key_file(
+    backend: &dyn Backend,
+    key: &str,
+    password: &str,
+) -> io::Result<Option<MasterKey>> {
+    let key_file: KeyFile = serde_json::from_slice(&backend.get(key)?)?;
+    let salt = hex::decode(&key_file.salt).map_err(invalid_data)?;
+    let nonce = hex::decode(&key_file.nonce).map_err(invalid_data)?;
+    let data = hex::decode(&key_file.data).map_err(invalid_data)?;
+    let wrapping_key = derive_key(password, &salt).map_err(io::Error::other)?;
+    let Ok(plaintext) = decrypt_with_key(&wrapping_key, &nonce, &data) else {
+        return Ok(None);
+    };
+    let material: KeyMaterial = serde_json::from_slice(&plaintext)?;
+    Ok(Some(MasterKey {
+        data_key: decode_key(&material.data_key)?,
+        id_key: decode_key(&material.id_key)?,
+    }))
+}
+
+/// Object keys of all key files with their slot IDs.
+fn key_files(backend: &dyn Backend) -> io::Result<Vec<(Uuid, String)>> {
+    let mut files = Vec::new();
+    for key in backend.list("keys")? {
+        if let Some(id) = key
+            .strip_prefix("keys/")
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|stem| stem.parse().ok())
+        {
+            files.push((id, key));
+        }
+    }
+    Ok(files)
+}
+
+/// Unlock the repository master key with `password` by trying every key file.
+/// Returns the ID of the matching slot along with the key.
+pub(crate) fn unlock(backend: &dyn Backend, password: &str) -> io::Result<(Uuid, MasterKey)> {
+    let files = key_files(backend)?;
+    if files.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "repository has no key files"));
+    }
+    for (id, key) in files {
+        if let Some(master) = unwrap_key_file(backend, &key, password)? {
+            return Ok((id, master));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong repository password"))
+}
+
+/// List the key slots of a repository, oldest first.
+pub(crate) fn list_key_slots(backend: &dyn Backend) -> io::Result<Vec<KeySlot>> {
+    let mut slots = Vec::new();
+    for (id, key) in key_files(backend)? {
+        let key_file: KeyFile = serde_json::from_slice(&backend.get(&key)?)?;
+        slots.push(KeySlot { id, created: key_file.created, hostname: key_file.hostname });
+    }
+    slots.sort_by_key(|s| s.created);
+    Ok(slots)
+}
+
+/// Delete a key slot. Refuses to delete the last remaining slot.
+pub(crate) fn remove_key_file(backend: &dyn Backend, id: &Uuid) -> io::Result<()> {
+    let files = key_files(backend)?;
+    let Some((_, key)) = files.iter().find(|(slot, _)| slot == id) else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no key slot {}", id)));
+    };
+    if files.len() == 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "refusing to remove the last key slot",
+        ));
+    }
+    backend.delete(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_local::LocalBackend;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_wrap_and_unlock() -> io::Result<()> {
+        let temp = tempdir()?;
+        let backend = LocalBackend::new(temp.path());
+        let master = MasterKey::generate();
+        let slot = add_key_file(&backend, &master, "pw")?;
+        let (unlocked_slot, unlocked) = unlock(&backend, "pw")?;
+        assert_eq!(unlocked_slot, slot);
+        assert_eq!(unlocked.data_key, master.data_key);
+        assert_eq!(unlocked.id_key, master.id_key);
+        let err = unlock(&backend, "wrong").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_slots() -> io::Result<()> {
+        let temp = tempdir()?;
+        let backend = LocalBackend::new(temp.path());
+        let master = MasterKey::generate();
+        let first = add_key_file(&backend, &master, "alice")?;
+        assert!(remove_key_file(&backend, &first).is_err());
+        let second = add_key_file(&backend, &master, "bob")?;
+        assert_eq!(list_key_slots(&backend)?.len(), 2);
+        assert_eq!(unlock(&backend, "bob")?.0, second);
+        remove_key_file(&backend, &first)?;
+        assert!(unlock(&backend, "alice").is_err());
+        assert_eq!(unlock(&backend, "bob")?.1.data_key, master.data_key);
+        Ok(())
+    }
+}
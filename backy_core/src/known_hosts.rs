@@ -0,0 +1,149 @@
+// Known hosts module: verify SSH server host keys before authenticating
+//
+// A host key is looked up in the user's OpenSSH known_hosts file and in an
+// app-managed trust store. Unknown keys are only accepted when the caller
+// passes back the exact fingerprint that was shown to the user (trust on
+// first use); they are then recorded in the trust store. A key that differs
+// from a recorded one is always rejected.
+
+use crate::sftp::SftpError;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use directories::{BaseDirs, ProjectDirs};
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, Session};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How the server's host key is verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostKeyPolicy {
+    /// Read-only known_hosts files, e.g. `~/.ssh/known_hosts`.
+    pub known_hosts: Vec<PathBuf>,
+    /// known_hosts file where newly trusted keys are recorded. Also consulted.
+    pub trust_store: PathBuf,
+    /// Fingerprint the user confirmed for a host that is not known yet.
+    pub accept_fingerprint: Option<String>,
+}
+
+impl Default for HostKeyPolicy {
+    /// The user's `~/.ssh/known_hosts` plus a trust store in the app data
+    /// directory.
+    fn default() -> Self {
+        let known_hosts = BaseDirs::new()
+            .map(|d| d.home_dir().join(".ssh").join("known_hosts"))
+            .into_iter()
+            .collect();
+        let trust_store = ProjectDirs::from("com", "backy", "Backy")
+            .map(|d| d.data_dir().join("known_hosts"))
+            .unwrap_or_else(|| PathBuf::from("known_hosts"));
+        HostKeyPolicy { known_hosts, trust_store, accept_fingerprint: None }
+    }
+}
+
+/// Name of a host in known_hosts files: `host`, or `[host]:port` for
+/// non-default ports.
+fn host_entry(host: &str, port: u16) -> String {
+    if port == 22 { host.to_string() } else { format!("[{}]:{}", host, port) }
+}
+
+/// OpenSSH-style fingerprint of a SHA-256 host key hash.
+fn fingerprint(hash: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(hash))
+}
+
+/// Look `key` up in every existing file of `files`.
+fn lookup(
+    session: &Session,
+    files: &[PathBuf],
+    host: &str,
+    port: u16,
+    key: &[u8],
+) -> Result<CheckResult, SftpError> {
+    let mut known = session.known_hosts()?;
+    for file in files.iter().filter(|f| f.exists()) {
+        known.read_file(file, KnownHostFileKind::OpenSSH)?;
+    }
+    Ok(known.check_port(host, port, key))
+}
+
+/// Append `key` for `host:port` to the known_hosts file `file`.
+fn remember(
+    session: &Session,
+    file: &Path,
+    host: &str,
+    port: u16,
+    key: &[u8],
+    key_type: HostKeyType,
+) -> Result<(), SftpError> {
+    let mut known = session.known_hosts()?;
+    if file.exists() {
+        known.read_file(file, KnownHostFileKind::OpenSSH)?;
+    }
+    known.add(&host_entry(host, port), key, "added by backy", key_type.into())?;
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    known.write_file(file, KnownHostFileKind::OpenSSH)?;
+    Ok(())
+}
+
+/// Check the host key of a session that completed its handshake.
+pub(crate) fn verify_host_key(
+    session: &Session,
+    host: &str,
+    port: u16,
+    policy: &HostKeyPolicy,
+) -> Result<(), SftpError> {
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| SftpError::Connection("Server sent no host key".to_string()))?;
+    let hash = session
+        .host_key_hash(HashType::Sha256)
+        .ok_or_else(|| SftpError::Connection("Cannot hash the server host key".to_string()))?;
+    let fingerprint = fingerprint(hash);
+    let mut files = policy.known_hosts.clone();
+    files.push(policy.trust_store.clone());
+    match lookup(session, &files, host, port, key)? {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => {
+            Err(SftpError::HostKeyMismatch { host: host_entry(host, port), fingerprint })
+        }
+        CheckResult::NotFound if policy.accept_fingerprint.as_deref() == Some(&fingerprint) => {
+            remember(session, &policy.trust_store, host, port, key, key_type)
+        }
+        CheckResult::NotFound => {
+            Err(SftpError::UnknownHostKey { host: host_entry(host, port), fingerprint })
+        }
+        CheckResult::Failure => {
+            Err(SftpError::Connection("Host key lookup failed".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fingerprint_and_host_entry() {
+        assert_eq!(fingerprint(&[0u8; 32]), "SHA256:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+        assert_eq!(host_entry("example.com", 22), "example.com");
+        assert_eq!(host_entry("example.com", 2222), "[example.com]:2222");
+    }
+
+    #[test]
+    fn test_remember_and_lookup() -> Result<(), SftpError> {
+        let temp = tempdir()?;
+        let store = temp.path().join("backy").join("known_hosts");
+        let files = vec![temp.path().join("missing"), store.clone()];
+        let session = Session::new()?;
+        let key = b"host key bytes".to_vec();
+        assert!(matches!(lookup(&session, &files, "h", 2222, &key)?, CheckResult::NotFound));
+        remember(&session, &store, "h", 2222, &key, HostKeyType::Ed25519)?;
+        assert!(matches!(lookup(&session, &files, "h", 2222, &key)?, CheckResult::Match));
+        assert!(matches!(lookup(&session, &files, "h", 2222, b"other key")?, CheckResult::Mismatch));
+        assert!(matches!(lookup(&session, &files, "h", 22, &key)?, CheckResult::NotFound));
+        Ok(())
+    }
+}
@@ -1,14 +1,81 @@
+pub use uuid::Uuid;
+
 mod chunker;
-pub use chunker::chunk_file;
+pub use chunker::{chunk_file, chunk_reader, Chunk, ChunkIter};
+
+mod backend;
+pub use backend::{Backend, ObjectInfo};
+
+mod blob_id;
+pub use blob_id::BlobId;
+
+mod compression;
+pub use compression::{compress, decompress, Compression, CompressionMode};
+
+mod envelope;
+pub use envelope::{Algorithm, Envelope, EnvelopeError};
 
 mod crypto;
-pub use crypto::{encrypt, decrypt};
+pub use crypto::{
+    encrypt, decrypt, derive_key, encrypt_with_key, decrypt_with_key, encrypt_blob, decrypt_blob,
+};
+
+mod pack;
+pub use pack::{PackEntry, PACK_TARGET_SIZE};
+
+mod index;
+
+mod keys;
+pub use keys::{KeySlot, MasterKey};
+
+mod lock;
+pub use lock::{Lock, RepoLock, STALE_AFTER};
 
 mod repository;
-pub use repository::{init_repo, save_blob, list_blobs};
+pub use repository::{init_repo, open_repo, Repository};
+
+mod snapshot;
+pub use snapshot::{
+    Snapshot, Tree, Node, NodeKind, save_tree, load_tree, save_snapshot, load_snapshot,
+    list_snapshots, remove_snapshot,
+};
+
+mod backup;
+pub use backup::{backup, BackupSummary};
+
+mod restore;
+pub use restore::{restore, OverwritePolicy, RestoreOptions, RestoreSummary};
+
+mod check;
+pub use check::{check, CheckOptions, CheckProblem, CheckReport};
+
+mod forget;
+pub use forget::{
+    apply_policy, forget, parse_duration, ForgetGroup, RetentionPolicy, SnapshotDecision,
+};
+
+mod prune;
+pub use prune::{prune, PruneOptions, PruneSummary};
 
 mod storage_local;
-pub use storage_local::save_blob_local;
+pub use storage_local::{save_blob_local, LocalBackend};
+
+#[cfg(feature = "sftp")]
+mod sftp;
+#[cfg(feature = "sftp")]
+pub use sftp::{
+    KEEPALIVE_INTERVAL, Resume, SftpAuth, SftpBackend, SftpClient, SftpError, TransferSummary,
+};
+
+#[cfg(feature = "sftp")]
+mod sftp_pool;
+#[cfg(feature = "sftp")]
+pub use sftp_pool::SftpPool;
+
+#[cfg(feature = "sftp")]
+mod known_hosts;
+#[cfg(feature = "sftp")]
+pub use known_hosts::HostKeyPolicy;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
@@ -16,11 +83,10 @@ pub fn add(left: u64, right: u64) -> u64 {
 
 pub fn backup_start(source: &str) -> Result<String, std::io::Error> {
     let output = std::process::Command::new("kopia")
-        .args(&["snapshot", "create", source, "--json"])
+        .args(["snapshot", "create", source, "--json"])
         .output()?;
     if !output.status.success() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        return Err(std::io::Error::other(
             format!("kopia failed with exit code: {}", output.status),
         ));
     }
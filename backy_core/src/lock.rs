@@ -0,0 +1,193 @@
+// Lock module: coordinate processes working on the same repository
+//
+// Locks are objects under `locks/` in the repository itself, so they work
+// for every backend. Any number of shared locks may coexist; an exclusive
+// lock excludes all others. A lock is written first and conflicts are
+// checked afterwards, so two processes racing for the repository both see
+// each other and back off instead of both proceeding.
+//
+// Holders refresh their locks while working. A lock that has not been
+// refreshed for `STALE_AFTER`, or whose process is gone from this host, is
+// stale and ignored.
+
+use crate::backend::Backend;
+use crate::repository::Repository;
+use crate::snapshot::local_hostname;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::process;
+use uuid::Uuid;
+
+/// Age after which a lock that was not refreshed is considered stale.
+pub const STALE_AFTER: Duration = Duration::minutes(30);
+
+/// Age at which holders refresh their locks.
+pub(crate) const REFRESH_AFTER: Duration = Duration::minutes(5);
+
+/// A lock held on a repository.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lock {
+    pub id: Uuid,
+    pub exclusive: bool,
+    pub hostname: String,
+    pub pid: u32,
+    /// When the lock was taken or last refreshed.
+    pub time: DateTime<Utc>,
+}
+
+/// Whether a process with this PID runs on this machine, if that can be
+/// determined.
+fn process_exists(pid: u32) -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        Some(std::path::Path::new("/proc").join(pid.to_string()).exists())
+    } else {
+        None
+    }
+}
+
+impl Lock {
+    fn new(exclusive: bool) -> Self {
+        Lock {
+            id: Uuid::new_v4(),
+            exclusive,
+            hostname: local_hostname(),
+            pid: process::id(),
+            time: Utc::now(),
+        }
+    }
+
+    /// Whether the holder of the lock is presumably gone.
+    pub fn is_stale(&self) -> bool {
+        if Utc::now() - self.time > STALE_AFTER {
+            return true;
+        }
+        self.hostname == local_hostname() && process_exists(self.pid) == Some(false)
+    }
+
+    fn conflicts_with(&self, other: &Lock) -> bool {
+        self.id != other.id && (self.exclusive || other.exclusive) && !other.is_stale()
+    }
+}
+
+fn lock_key(id: &Uuid) -> String {
+    format!("locks/{}.json", id)
+}
+
+/// Write `lock`, replacing its previous version.
+pub(crate) fn write_lock(backend: &dyn Backend, lock: &Lock) -> io::Result<()> {
+    backend.put(&lock_key(&lock.id), &serde_json::to_vec_pretty(lock)?)
+}
+
+/// All locks in the repository, including stale ones. Locks removed while
+/// listing are skipped.
+pub(crate) fn list_locks(backend: &dyn Backend) -> io::Result<Vec<Lock>> {
+    let mut locks = Vec::new();
+    for key in backend.list("locks")? {
+        if !key.ends_with(".json") {
+            continue;
+        }
+        match backend.get(&key) {
+            Ok(data) => locks.push(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    locks.sort_by_key(|l: &Lock| l.time);
+    Ok(locks)
+}
+
+/// Whether `id` is still in the repository, i.e. nobody removed it.
+pub(crate) fn lock_exists(backend: &dyn Backend, id: &Uuid) -> io::Result<bool> {
+    backend.exists(&lock_key(id))
+}
+
+pub(crate) fn remove_lock(backend: &dyn Backend, id: &Uuid) -> io::Result<()> {
+    match backend.delete(&lock_key(id)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Take a lock, failing with `ResourceBusy` if a conflicting lock is held.
+pub(crate) fn acquire(backend: &dyn Backend, exclusive: bool) -> io::Result<Lock> {
+    let lock = Lock::new(exclusive);
+    write_lock(backend, &lock)?;
+    let conflict = match list_locks(backend) {
+        Ok(locks) => locks.into_iter().find(|other| lock.conflicts_with(other)),
+        Err(e) => {
+            let _ = remove_lock(backend, &lock.id);
+            return Err(e);
+        }
+    };
+    if let Some(other) = conflict {
+        remove_lock(backend, &lock.id)?;
+        return Err(io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            format!(
+                "repository is locked {} by PID {} on {} since {}",
+                if other.exclusive { "exclusively" } else { "shared" },
+                other.pid,
+                other.hostname,
+                other.time.format("%Y-%m-%d %H:%M:%S UTC"),
+            ),
+        ));
+    }
+    Ok(lock)
+}
+
+/// A lock held on a repository, released when dropped.
+pub struct RepoLock<'a> {
+    pub(crate) repo: &'a Repository,
+    pub(crate) id: Uuid,
+}
+
+impl Drop for RepoLock<'_> {
+    /// Remove the lock. Errors are ignored; a lock left behind goes stale.
+    fn drop(&mut self) {
+        let _ = self.repo.release_lock(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_local::LocalBackend;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_shared_and_exclusive_locks() -> io::Result<()> {
+        let temp = tempdir()?;
+        let backend = LocalBackend::new(temp.path());
+        let a = acquire(&backend, false)?;
+        let b = acquire(&backend, false)?;
+        let err = acquire(&backend, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(list_locks(&backend)?.len(), 2);
+
+        remove_lock(&backend, &a.id)?;
+        remove_lock(&backend, &b.id)?;
+        let exclusive = acquire(&backend, true)?;
+        assert!(acquire(&backend, false).is_err());
+        assert_eq!(list_locks(&backend)?, [exclusive]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_locks_are_ignored() -> io::Result<()> {
+        let temp = tempdir()?;
+        let backend = LocalBackend::new(temp.path());
+        let mut old = Lock::new(true);
+        old.hostname = "elsewhere".into();
+        old.time = Utc::now() - STALE_AFTER - Duration::minutes(1);
+        write_lock(&backend, &old)?;
+        assert!(old.is_stale());
+        assert!(acquire(&backend, true).is_ok());
+
+        let mut fresh = Lock::new(true);
+        fresh.hostname = "elsewhere".into();
+        assert!(!fresh.is_stale());
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+// Pack module: aggregate many encrypted blobs into a single pack file
+//
+// Layout of a pack file:
+//
+//   blob envelope 1 | blob envelope 2 | ... | header envelope | header length
+//
+// Every blob is stored as its own envelope (see `envelope`), so one blob can
+// be read back with a single ranged read. The header is an envelope as well;
+// its plaintext is the JSON list of `PackEntry`s describing the blobs. The
+// header length is appended as a 4-byte little-endian integer so readers can
+// find the header from the end of the file.
+//
+// A pack is named after the unkeyed BLAKE3 hash of its contents.
+
+use crate::backend::Backend;
+use crate::blob_id::BlobId;
+use crate::compression::CompressionMode;
+use crate::crypto::{decrypt_blob, encrypt_blob};
+use crate::keys::MasterKey;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Size at which a pack being filled is written out.
+pub const PACK_TARGET_SIZE: usize = 32 * 1024 * 1024;
+
+/// Location of one blob inside a pack.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackEntry {
+    pub id: BlobId,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A pack being filled in memory.
+#[derive(Default)]
+pub(crate) struct PackWriter {
+    data: Vec<u8>,
+    entries: Vec<PackEntry>,
+}
+
+impl PackWriter {
+    /// Append an encrypted blob envelope.
+    pub(crate) fn add(&mut self, id: BlobId, envelope: &[u8]) {
+        self.entries.push(PackEntry {
+            id,
+            offset: self.data.len() as u64,
+            length: envelope.len() as u64,
+        });
+        self.data.extend_from_slice(envelope);
+    }
+
+    /// Bytes of blob data buffered so far.
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn entries(&self) -> &[PackEntry] {
+        &self.entries
+    }
+
+    /// Envelope of a buffered blob.
+    pub(crate) fn get(&self, id: &BlobId) -> Option<&[u8]> {
+        let entry = self.entries.iter().find(|e| e.id == *id)?;
+        let start = entry.offset as usize;
+        Some(&self.data[start..start + entry.length as usize])
+    }
+
+    /// Append the encrypted header and return the pack name, its bytes and
+    /// the entries it contains.
+    pub(crate) fn finish(
+        self,
+        key: &MasterKey,
+        mode: CompressionMode,
+    ) -> io::Result<(String, Vec<u8>, Vec<PackEntry>)> {
+        let PackWriter { mut data, entries } = self;
+        let header = encrypt_blob(&serde_json::to_vec(&entries)?, &key.data_key, key.key_id(), mode)
+            .map_err(io::Error::other)?;
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        let name = blake3::hash(&data).to_hex().to_string();
+        Ok((name, data, entries))
+    }
+}
+
+/// Decrypt the header of a pack of `size` bytes and return its entries,
+/// reading only the trailer and the header through `read_range`.
+fn parse_header(
+    size: u64,
+    key: &MasterKey,
+    read_range: impl Fn(u64, u64) -> io::Result<Vec<u8>>,
+) -> io::Result<Vec<PackEntry>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let Some(split) = size.checked_sub(4) else {
+        return Err(invalid("pack is truncated"));
+    };
+    let trailer = read_range(split, 4)?;
+    let trailer: [u8; 4] = trailer.try_into().map_err(|_| invalid("pack is truncated"))?;
+    let header_len = u64::from(u32::from_le_bytes(trailer));
+    let Some(start) = split.checked_sub(header_len) else {
+        return Err(invalid("pack header length out of range"));
+    };
+    let header = decrypt_blob(&read_range(start, header_len)?, &key.data_key, key.key_id())
+        .map_err(|e| invalid(&format!("pack header: {}", e)))?;
+    Ok(serde_json::from_slice(&header)?)
+}
+
+/// Decrypt the header of the pack stored under `object_key` and return its
+/// entries. Only the header is downloaded, not the blobs.
+pub(crate) fn load_pack_header(
+    backend: &dyn Backend,
+    object_key: &str,
+    key: &MasterKey,
+) -> io::Result<Vec<PackEntry>> {
+    let size = backend.stat(object_key)?.size;
+    parse_header(size, key, |offset, length| backend.get_range(object_key, offset, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_pack_header(pack: &[u8], key: &MasterKey) -> io::Result<Vec<PackEntry>> {
+        parse_header(pack.len() as u64, key, |offset, length| {
+            Ok(pack[offset as usize..(offset + length) as usize].to_vec())
+        })
+    }
+
+    #[test]
+    fn test_pack_roundtrip() -> io::Result<()> {
+        let key = MasterKey::generate();
+        let mut writer = PackWriter::default();
+        let blobs: Vec<_> = (0u8..3)
+            .map(|i| {
+                let id = BlobId::from([i; 32]);
+                let env = encrypt_blob(&[i; 100], &key.data_key, key.key_id(), CompressionMode::Off)
+                    .unwrap();
+                (id, env)
+            })
+            .collect();
+        for (id, env) in &blobs {
+            writer.add(*id, env);
+        }
+        assert_eq!(writer.get(&blobs[1].0), Some(blobs[1].1.as_slice()));
+
+        let (name, pack, entries) = writer.finish(&key, CompressionMode::default())?;
+        assert_eq!(name, blake3::hash(&pack).to_hex().to_string());
+        assert_eq!(read_pack_header(&pack, &key)?, entries);
+        for (entry, (id, env)) in entries.iter().zip(&blobs) {
+            assert_eq!(entry.id, *id);
+            let start = entry.offset as usize;
+            assert_eq!(&pack[start..start + entry.length as usize], env.as_slice());
+        }
+        assert!(read_pack_header(&pack, &MasterKey::generate()).is_err());
+        assert!(read_pack_header(&pack[..3], &key).is_err());
+        Ok(())
+    }
+}
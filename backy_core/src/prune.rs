@@ -0,0 +1,274 @@
+// Prune module: remove data that no snapshot references any more
+//
+// Blobs reachable from the remaining snapshots (their trees and the chunks
+// of the files in them) are kept. Packs holding only unreachable blobs are
+// deleted; packs mixing both are repacked so that only the reachable blobs
+// are copied into new packs.
+//
+// Packs missing from the index, left behind by interrupted backups or lost
+// index segments, are indexed again from their headers and then treated like
+// the others. Those whose header cannot be read are only reported and left
+// for `check` and `rebuild_index`. Temporary objects of interrupted writes
+// are deleted.
+
+use crate::blob_id::BlobId;
+use crate::index::IndexEntry;
+use crate::repository::Repository;
+use crate::snapshot::{NodeKind, list_snapshots, load_tree};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// Options for `prune`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PruneOptions {
+    /// Only compute what would be removed.
+    pub dry_run: bool,
+}
+
+/// What `prune` removed, or would remove in a dry run.
+#[derive(Debug, Clone, Default)]
+pub struct PruneSummary {
+    pub snapshots: u64,
+    pub blobs_kept: u64,
+    pub blobs_removed: u64,
+    /// Packs deleted because none of their blobs is used.
+    pub packs_deleted: u64,
+    /// Packs rewritten without their unused blobs.
+    pub packs_repacked: u64,
+    /// Packs found in storage but missing from the index, indexed again
+    /// from their headers.
+    pub packs_unindexed: u64,
+    /// Packs missing from the index whose header cannot be read, e.g.
+    /// because they are damaged. They are left alone.
+    pub unreadable_packs: Vec<String>,
+    /// Temporary objects left by interrupted writes.
+    pub temp_files_deleted: u64,
+    /// Approximate storage reclaimed, in bytes.
+    pub bytes_freed: u64,
+}
+
+/// IDs of all blobs used by the snapshots in the repository.
+fn reachable_blobs(repo: &Repository) -> io::Result<(u64, HashSet<BlobId>)> {
+    let snapshots = list_snapshots(repo)?;
+    let mut used = HashSet::new();
+    let mut trees: Vec<BlobId> = snapshots.iter().map(|s| s.tree).collect();
+    while let Some(id) = trees.pop() {
+        if !used.insert(id) {
+            continue;
+        }
+        repo.refresh_locks()?;
+        for node in load_tree(repo, &id)?.nodes {
+            match node.kind {
+                NodeKind::File { chunks, .. } => used.extend(chunks),
+                NodeKind::Dir { subtree } => trees.push(subtree),
+            }
+        }
+    }
+    Ok((snapshots.len() as u64, used))
+}
+
+/// Prefixes searched for temporary objects. Locks are left alone since
+/// other processes write theirs while trying to get one.
+const TEMP_PREFIXES: [&str; 3] = ["packs", "index", "snapshots"];
+
+/// Packs in storage that the index does not list, with their entries, and
+/// the names of those whose header cannot be decrypted or parsed.
+#[allow(clippy::type_complexity)]
+fn unindexed_packs(
+    repo: &Repository,
+    indexed: &HashSet<&str>,
+) -> io::Result<(Vec<(String, Vec<IndexEntry>)>, Vec<String>)> {
+    let mut packs = Vec::new();
+    let mut unreadable = Vec::new();
+    for key in repo.backend().list("packs")? {
+        let Some(name) = Repository::pack_name(&key).filter(|n| !indexed.contains(n)) else {
+            continue;
+        };
+        match repo.read_pack_entries(name) {
+            Ok(entries) => packs.push((name.to_string(), entries)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => unreadable.push(name.to_string()),
+            Err(e) => return Err(e),
+        }
+    }
+    unreadable.sort();
+    Ok((packs, unreadable))
+}
+
+/// Remove blobs that no snapshot references. Fails without deleting
+/// anything if a snapshot refers to a tree that cannot be read. Holds an
+/// exclusive lock on the repository, or a shared one for a dry run.
+pub fn prune(repo: &Repository, options: &PruneOptions) -> io::Result<PruneSummary> {
+    let _lock = if options.dry_run { repo.lock_shared()? } else { repo.lock_exclusive()? };
+    repo.flush()?;
+    let (unindexed, unreadable_packs) = {
+        let index = repo.read_index()?;
+        let indexed: HashSet<&str> = index.iter().map(|e| e.pack.as_str()).collect();
+        unindexed_packs(repo, &indexed)?
+    };
+    // Index the blobs of those packs first, so snapshots referring to them
+    // can be read and the blobs they need are kept.
+    let recovered = unindexed.iter().flat_map(|(_, entries)| entries.iter().cloned()).collect();
+    repo.add_to_index(recovered, !options.dry_run)?;
+
+    let (snapshots, used) = reachable_blobs(repo)?;
+    let mut summary = PruneSummary {
+        snapshots,
+        packs_unindexed: unindexed.len() as u64,
+        unreadable_packs,
+        ..Default::default()
+    };
+
+    // Bytes of used blobs per pack, with unused ones counted separately.
+    // Only the first copy of a blob stored in several packs is used.
+    let index = repo.read_index()?;
+    let mut packs: HashMap<&str, (u64, u64)> =
+        unindexed.iter().map(|(pack, _)| (pack.as_str(), (0, 0))).collect();
+    let mut seen = HashSet::new();
+    for entry in &index {
+        let (kept, unused) = packs.entry(&entry.pack).or_default();
+        if used.contains(&entry.id) && seen.insert(entry.id) {
+            *kept += entry.length;
+            summary.blobs_kept += 1;
+        } else {
+            *unused += entry.length;
+            summary.blobs_removed += 1;
+        }
+    }
+
+    let mut rewrite = HashSet::new();
+    for (pack, (kept, unused)) in packs {
+        if kept > 0 && unused == 0 {
+            continue;
+        }
+        let size = repo.backend().stat(&Repository::pack_key(pack))?.size;
+        if kept == 0 {
+            summary.packs_deleted += 1;
+            summary.bytes_freed += size;
+        } else {
+            summary.packs_repacked += 1;
+            summary.bytes_freed += size.saturating_sub(kept);
+        }
+        rewrite.insert(pack.to_string());
+    }
+
+    let mut temp_files = Vec::new();
+    for prefix in TEMP_PREFIXES {
+        temp_files.extend(repo.backend().list(prefix)?.into_iter().filter(|k| k.ends_with(".tmp")));
+    }
+    summary.temp_files_deleted = temp_files.len() as u64;
+    for key in &temp_files {
+        summary.bytes_freed += repo.backend().stat(key)?.size;
+    }
+
+    if !options.dry_run {
+        if !rewrite.is_empty() {
+            repo.repack(&rewrite, &used)?;
+        }
+        for key in &temp_files {
+            match repo.backend().delete(key) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::backup;
+    use crate::check::{CheckOptions, CheckProblem, check};
+    use crate::compression::CompressionMode;
+    use crate::snapshot::remove_snapshot;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_prune_removes_unreferenced_blobs() -> io::Result<()> {
+        let src = tempdir()?;
+        let temp = tempdir()?;
+        let mut repo = Repository::init(temp.path(), "pw")?;
+        repo.set_pack_size(1000);
+        repo.set_compression(CompressionMode::Off);
+        fs::write(src.path().join("kept.txt"), vec![1u8; 700])?;
+        fs::write(src.path().join("gone.txt"), vec![2u8; 700])?;
+        let (first, _) = backup(&repo, &[src.path()], Vec::new())?;
+        fs::remove_file(src.path().join("gone.txt"))?;
+        let (second, _) = backup(&repo, &[src.path()], Vec::new())?;
+        let orphan = repo.save_blob(b"never referenced")?;
+
+        let summary = prune(&repo, &PruneOptions { dry_run: true })?;
+        assert_eq!(summary.blobs_removed, 1);
+        assert!(summary.bytes_freed > 0);
+        assert!(repo.has_blob(&orphan)?);
+
+        remove_snapshot(&repo, &first.id)?;
+        let dry = prune(&repo, &PruneOptions { dry_run: true })?;
+        let summary = prune(&repo, &PruneOptions::default())?;
+        assert_eq!(summary.snapshots, 1);
+        assert_eq!(summary.blobs_removed, dry.blobs_removed);
+        assert!(summary.blobs_removed >= 4);
+        assert!(!repo.has_blob(&orphan)?);
+
+        let report = check(&repo, &CheckOptions { read_data_percent: 100 })?;
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.blobs as u64, summary.blobs_kept);
+        let again = prune(&repo, &PruneOptions::default())?;
+        assert_eq!(again.blobs_removed, 0);
+
+        let restored = tempdir()?;
+        crate::restore::restore(&repo, &second.id, restored.path(), &Default::default())?;
+        let name = src.path().file_name().unwrap();
+        assert_eq!(fs::read(restored.path().join(name).join("kept.txt"))?, vec![1u8; 700]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_handles_unindexed_packs_and_temp_files() -> io::Result<()> {
+        let src = tempdir()?;
+        let temp = tempdir()?;
+        let mut repo = Repository::init(temp.path(), "pw")?;
+        repo.set_pack_size(1000);
+        repo.set_compression(CompressionMode::Off);
+        fs::write(src.path().join("a.txt"), vec![1u8; 700])?;
+        fs::write(src.path().join("b.txt"), vec![2u8; 700])?;
+        let (snapshot, _) = backup(&repo, &[src.path()], Vec::new())?;
+        let orphan = repo.save_blob(b"never referenced")?;
+        repo.flush()?;
+        let packs = repo.backend().list("packs")?.len() as u64;
+
+        // Lose the index and leave debris of interrupted writes behind.
+        fs::remove_dir_all(temp.path().join("index"))?;
+        let shard = temp.path().join("packs").join("ab");
+        fs::create_dir_all(&shard)?;
+        let junk = shard.join(format!("{}.pack", "ab".repeat(32)));
+        fs::write(&junk, b"junk")?;
+        let leftover = shard.join("leftover.pack.0123.tmp");
+        fs::write(&leftover, b"partial")?;
+
+        let repo = Repository::open(temp.path(), "pw")?;
+        let dry = prune(&repo, &PruneOptions { dry_run: true })?;
+        assert_eq!(dry.packs_unindexed, packs);
+        let junk_name = Repository::pack_name(junk.to_str().unwrap()).unwrap().to_string();
+        assert_eq!(dry.unreadable_packs, std::slice::from_ref(&junk_name));
+        assert_eq!(dry.temp_files_deleted, 1);
+        assert!(junk.exists() && leftover.exists());
+
+        let summary = prune(&repo, &PruneOptions::default())?;
+        assert_eq!(summary.blobs_removed, 1);
+        assert_eq!(summary.bytes_freed, dry.bytes_freed);
+        assert!(junk.exists() && !leftover.exists());
+        assert!(!repo.has_blob(&orphan)?);
+
+        let report = check(&repo, &CheckOptions { read_data_percent: 100 })?;
+        assert_eq!(report.problems, [CheckProblem::OrphanedPack { pack: junk_name }]);
+        let restored = tempdir()?;
+        crate::restore::restore(&repo, &snapshot.id, restored.path(), &Default::default())?;
+        let name = src.path().file_name().unwrap();
+        assert_eq!(fs::read(restored.path().join(name).join("b.txt"))?, vec![2u8; 700]);
+        Ok(())
+    }
+}
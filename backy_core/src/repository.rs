@@ -1,92 +1,841 @@
-// Repository module: store blobs and maintain an index
+// Repository module: store blobs in pack files and maintain an index
 
+use crate::backend::Backend;
+use crate::blob_id::BlobId;
+use crate::compression::CompressionMode;
+use crate::crypto::{decrypt_blob, encrypt_blob};
+use crate::index::{CONSOLIDATE_AFTER, Index, IndexEntry};
+use crate::keys::{KeySlot, MasterKey, add_key_file, list_key_slots, remove_key_file, unlock};
+use crate::lock::{self, Lock, REFRESH_AFTER, RepoLock};
+use crate::pack::{PACK_TARGET_SIZE, PackWriter, load_pack_header};
+use crate::storage_local::LocalBackend;
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
-use std::{env, fs, io, path::PathBuf};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::{env, iter, mem};
 use uuid::Uuid;
 
-/// A single entry in the repository index.
+/// Repository format version written by `init`.
+const REPO_VERSION: u32 = 3;
+
+/// Blobs saved but not yet written out.
+#[derive(Default)]
+struct Pending {
+    /// Full packs waiting to be uploaded together.
+    full: Vec<PackWriter>,
+    /// Pack being filled.
+    current: PackWriter,
+}
+
+impl Pending {
+    fn writers(&self) -> impl Iterator<Item = &PackWriter> {
+        self.full.iter().chain(iter::once(&self.current))
+    }
+
+    /// Envelope of a buffered blob.
+    fn get(&self, id: &BlobId) -> Option<&[u8]> {
+        self.writers().find_map(|w| w.get(id))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.full.is_empty() && self.current.is_empty()
+    }
+}
+
+/// Repository configuration, written once by `init_repo`.
 #[derive(Serialize, Deserialize)]
-struct IndexEntry {
+struct RepoConfig {
     id: Uuid,
-    filename: String,
-    length: usize,
+    version: u32,
 }
 
-/// Determine the repo directory and index JSON path.
-fn get_paths() -> io::Result<(PathBuf, PathBuf)> {
+/// Determine the repo directory from XDG_DATA_HOME or the platform data dir.
+fn default_repo_dir() -> io::Result<PathBuf> {
     let base = env::var("XDG_DATA_HOME")
         .map(PathBuf::from)
         .or_else(|_| {
             ProjectDirs::from("com", "backy", "Backy")
                 .map(|d| d.data_dir().to_path_buf())
-                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Cannot determine project directory"))
+                .ok_or_else(|| io::Error::other("Cannot determine project directory"))
         })?;
-    let repo_dir = base.join("repo");
-    let index_file = repo_dir.join("index.json");
-    Ok((repo_dir, index_file))
+    Ok(base.join("repo"))
+}
+
+/// Object key of the repository configuration.
+const CONFIG: &str = "config.json";
+
+/// Object key of the single index file of version 2 repositories.
+const LEGACY_INDEX: &str = "index.json";
+
+/// Move the index of a version 2 repository into a segment and mark the
+/// repository as version 3. Safe to repeat if interrupted.
+fn upgrade_from_v2(backend: &dyn Backend, mut config: RepoConfig) -> io::Result<()> {
+    if backend.exists(LEGACY_INDEX)? {
+        let entries: Vec<IndexEntry> = serde_json::from_slice(&backend.get(LEGACY_INDEX)?)?;
+        Index::default().append(backend, entries)?;
+    }
+    config.version = REPO_VERSION;
+    backend.put(CONFIG, &serde_json::to_vec_pretty(&config)?)?;
+    match backend.delete(LEGACY_INDEX) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// An unlocked repository: its storage backend plus the master key.
+pub struct Repository {
+    backend: Box<dyn Backend>,
+    key: MasterKey,
+    /// Key slot that was used to unlock the repository.
+    key_id: Uuid,
+    compression: CompressionMode,
+    /// Blobs saved but not yet written out as packs.
+    pending: Mutex<Pending>,
+    pack_size: usize,
+    /// Index segments loaded so far.
+    index: Mutex<Index>,
+    /// Locks taken through this handle.
+    locks: Mutex<Vec<Lock>>,
+}
+
+impl Repository {
+    fn new(backend: Box<dyn Backend>, key: MasterKey, key_id: Uuid) -> Self {
+        Repository {
+            backend,
+            key,
+            key_id,
+            compression: CompressionMode::default(),
+            pending: Mutex::default(),
+            pack_size: PACK_TARGET_SIZE,
+            index: Mutex::default(),
+            locks: Mutex::default(),
+        }
+    }
+
+    /// Create a new repository in `backend` protected by `password`.
+    /// Writes the config and a key file holding a fresh master key. Fails
+    /// if a repository already exists there.
+    pub fn init_with(backend: Box<dyn Backend>, password: &str) -> io::Result<Self> {
+        if backend.exists(CONFIG)? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("repository already exists at {}", backend.location()),
+            ));
+        }
+        let key = MasterKey::generate();
+        let key_id = add_key_file(backend.as_ref(), &key, password)?;
+        let config = RepoConfig { id: Uuid::new_v4(), version: REPO_VERSION };
+        backend.put(CONFIG, &serde_json::to_vec_pretty(&config)?)?;
+        Ok(Self::new(backend, key, key_id))
+    }
+
+    /// Open the repository in `backend`, unlocking its master key with `password`.
+    /// Version 2 repositories are upgraded once unlocked.
+    pub fn open_with(backend: Box<dyn Backend>, password: &str) -> io::Result<Self> {
+        if !backend.exists(CONFIG)? {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no repository at {}", backend.location()),
+            ));
+        }
+        let config: RepoConfig = serde_json::from_slice(&backend.get(CONFIG)?)?;
+        if config.version != REPO_VERSION && config.version != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported repository version {}", config.version),
+            ));
+        }
+        let (key_id, key) = unlock(backend.as_ref(), password)?;
+        if config.version == 2 {
+            upgrade_from_v2(backend.as_ref(), config)?;
+        }
+        Ok(Self::new(backend, key, key_id))
+    }
+
+    /// Open the repository in `backend`, creating it first if there is none.
+    pub fn open_or_init_with(backend: Box<dyn Backend>, password: &str) -> io::Result<Self> {
+        if backend.exists(CONFIG)? {
+            Self::open_with(backend, password)
+        } else {
+            Self::init_with(backend, password)
+        }
+    }
+
+    /// Create a new repository in the local directory `dir`.
+    pub fn init(dir: &Path, password: &str) -> io::Result<Self> {
+        Self::init_with(Box::new(LocalBackend::new(dir)), password)
+    }
+
+    /// Open the repository in the local directory `dir`.
+    pub fn open(dir: &Path, password: &str) -> io::Result<Self> {
+        Self::open_with(Box::new(LocalBackend::new(dir)), password)
+    }
+
+    /// Open the repository in the local directory `dir`, creating it first
+    /// if there is none.
+    pub fn open_or_init(dir: &Path, password: &str) -> io::Result<Self> {
+        Self::open_or_init_with(Box::new(LocalBackend::new(dir)), password)
+    }
+
+    /// Storage backend of the repository.
+    pub fn backend(&self) -> &dyn Backend {
+        self.backend.as_ref()
+    }
+
+    /// Human-readable location of the repository.
+    pub fn location(&self) -> String {
+        self.backend.location()
+    }
+
+    /// ID of the key slot this repository was unlocked with.
+    pub fn key_id(&self) -> Uuid {
+        self.key_id
+    }
+
+    /// List all key slots.
+    pub fn list_keys(&self) -> io::Result<Vec<KeySlot>> {
+        list_key_slots(self.backend())
+    }
+
+    /// Add a key slot so `password` also unlocks this repository.
+    /// Returns the new slot ID.
+    pub fn add_password(&self, password: &str) -> io::Result<Uuid> {
+        add_key_file(self.backend(), &self.key, password)
+    }
+
+    /// Revoke a key slot. The last remaining slot cannot be removed.
+    pub fn remove_key(&self, id: &Uuid) -> io::Result<()> {
+        remove_key_file(self.backend(), id)
+    }
+
+    /// Replace the password of the current key slot.
+    /// Only the key file is rewritten; blob data is untouched.
+    pub fn change_password(&mut self, new_password: &str) -> io::Result<()> {
+        let new_id = self.add_password(new_password)?;
+        self.remove_key(&self.key_id)?;
+        self.key_id = new_id;
+        Ok(())
+    }
+
+    /// Take a shared lock, which only excludes exclusive locks. Fails with
+    /// `ResourceBusy` if another process holds the repository exclusively.
+    pub fn lock_shared(&self) -> io::Result<RepoLock<'_>> {
+        self.take_lock(false)
+    }
+
+    /// Take an exclusive lock. Fails with `ResourceBusy` if another process
+    /// holds any lock that is not stale.
+    pub fn lock_exclusive(&self) -> io::Result<RepoLock<'_>> {
+        self.take_lock(true)
+    }
+
+    fn take_lock(&self, exclusive: bool) -> io::Result<RepoLock<'_>> {
+        let lock = lock::acquire(self.backend(), exclusive)?;
+        let id = lock.id;
+        self.held_locks().push(lock);
+        // Others may have rewritten the index before we got the lock.
+        *self.index.lock().unwrap_or_else(PoisonError::into_inner) = Index::default();
+        Ok(RepoLock { repo: self, id })
+    }
+
+    /// A shared lock for writing packs and index segments, unless this
+    /// handle already holds a lock. Without one, `prune` could delete a pack
+    /// between its upload and the index update describing it.
+    fn write_lock(&self) -> io::Result<Option<RepoLock<'_>>> {
+        if self.held_locks().is_empty() {
+            self.lock_shared().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn held_locks(&self) -> MutexGuard<'_, Vec<Lock>> {
+        self.locks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn release_lock(&self, id: &Uuid) -> io::Result<()> {
+        self.held_locks().retain(|l| l.id != *id);
+        lock::remove_lock(self.backend(), id)
+    }
+
+    /// Rewrite the locks held through this handle that are due for a
+    /// refresh, so other processes do not consider them stale. Fails if one
+    /// of them was removed, e.g. by a forced unlock, since others may have
+    /// changed the repository in the meantime.
+    pub(crate) fn refresh_locks(&self) -> io::Result<()> {
+        let now = chrono::Utc::now();
+        for held in self.held_locks().iter_mut().filter(|l| now - l.time >= REFRESH_AFTER) {
+            if !lock::lock_exists(self.backend(), &held.id)? {
+                return Err(io::Error::other(format!(
+                    "lock {} was removed by another process",
+                    held.id
+                )));
+            }
+            held.time = now;
+            lock::write_lock(self.backend(), held)?;
+        }
+        Ok(())
+    }
+
+    /// Make the locks held through this handle look `age` old.
+    #[cfg(test)]
+    pub(crate) fn backdate_locks(&self, age: chrono::Duration) -> io::Result<()> {
+        for held in self.held_locks().iter_mut() {
+            held.time = chrono::Utc::now() - age;
+            lock::write_lock(self.backend(), held)?;
+        }
+        Ok(())
+    }
+
+    /// All locks in the repository, oldest first.
+    pub fn list_locks(&self) -> io::Result<Vec<Lock>> {
+        lock::list_locks(self.backend())
+    }
+
+    /// Remove stale locks, or with `force` every lock not held through
+    /// this handle. Forcing is only safe once the holders are known to be
+    /// gone. Returns the removed locks.
+    pub fn remove_locks(&self, force: bool) -> io::Result<Vec<Lock>> {
+        let held: Vec<Uuid> = self.held_locks().iter().map(|l| l.id).collect();
+        let mut removed = Vec::new();
+        for other in self.list_locks()? {
+            if !held.contains(&other.id) && (force || other.is_stale()) {
+                lock::remove_lock(self.backend(), &other.id)?;
+                removed.push(other);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Compression applied to blobs saved from now on.
+    pub fn set_compression(&mut self, mode: CompressionMode) {
+        self.compression = mode;
+    }
+
+    /// Size at which buffered blobs are written out as a pack.
+    pub fn set_pack_size(&mut self, bytes: usize) {
+        self.pack_size = bytes;
+    }
+
+    /// The index, loaded from the backend on first use. Segments written
+    /// by other processes later are only picked up by `read_index` and by
+    /// lookups of unknown blobs.
+    fn index(&self) -> io::Result<MutexGuard<'_, Index>> {
+        let mut index = self.index.lock().unwrap_or_else(PoisonError::into_inner);
+        if !index.is_loaded() {
+            index.update(self.backend())?;
+        }
+        Ok(index)
+    }
+
+    /// All index entries, including those added by other processes.
+    pub(crate) fn read_index(&self) -> io::Result<Vec<IndexEntry>> {
+        let mut index = self.index.lock().unwrap_or_else(PoisonError::into_inner);
+        index.update(self.backend())?;
+        Ok(index.entries().to_vec())
+    }
+
+    /// Content-derived ID of `data` in this repository.
+    pub fn blob_id(&self, data: &[u8]) -> BlobId {
+        BlobId::compute(&self.key.id_key, data)
+    }
+
+    fn pending(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn pack_key(name: &str) -> String {
+        format!("packs/{}/{}.pack", &name[..2], name)
+    }
+
+    /// Name of the pack stored under the object key `key`, if it is one.
+    pub(crate) fn pack_name(key: &str) -> Option<&str> {
+        key.rsplit('/').next().and_then(|n| n.strip_suffix(".pack"))
+    }
+
+    /// Write the buffered blobs as packs, uploading up to
+    /// `Backend::concurrency` of them at once, and return their index
+    /// entries.
+    fn upload_packs(&self, pending: &mut Pending) -> io::Result<Vec<IndexEntry>> {
+        let Pending { mut full, current } = mem::take(pending);
+        if !current.is_empty() {
+            full.push(current);
+        }
+        let mut objects = Vec::new();
+        let mut added = Vec::new();
+        for writer in full {
+            let (name, data, entries) = writer.finish(&self.key, self.compression)?;
+            objects.push((Self::pack_key(&name), data));
+            added.extend(entries.into_iter().map(|e| IndexEntry::new(&name, e)));
+        }
+        self.backend.put_all(&objects)?;
+        Ok(added)
+    }
+
+    /// Write the buffered blobs as packs and add them to the index.
+    fn write_packs(&self, pending: &mut Pending) -> io::Result<()> {
+        self.refresh_locks()?;
+        let added = self.upload_packs(pending)?;
+        self.index()?.append(self.backend(), added)
+    }
+
+    /// Remove the packs named in `packs`, first copying the blobs of theirs
+    /// listed in `keep` into new packs. The index is updated before the old
+    /// packs are deleted, so an interruption leaves unreferenced packs
+    /// behind at worst.
+    pub(crate) fn repack(&self, packs: &HashSet<String>, keep: &HashSet<BlobId>) -> io::Result<()> {
+        self.flush()?;
+        let (old, mut index): (Vec<_>, Vec<_>) =
+            self.read_index()?.into_iter().partition(|e| packs.contains(&e.pack));
+        let mut present: HashSet<BlobId> = index.iter().map(|e| e.id).collect();
+        let mut pending = Pending::default();
+        for entry in old.iter().filter(|e| keep.contains(&e.id)) {
+            if !present.insert(entry.id) {
+                continue;
+            }
+            self.refresh_locks()?;
+            let envelope =
+                self.backend.get_range(&Self::pack_key(&entry.pack), entry.offset, entry.length)?;
+            pending.current.add(entry.id, &envelope);
+            if pending.current.len() >= self.pack_size {
+                let full = mem::take(&mut pending.current);
+                pending.full.push(full);
+            }
+        }
+        if !pending.is_empty() {
+            index.extend(self.upload_packs(&mut pending)?);
+        }
+        self.index()?.replace(self.backend(), index)?;
+        for pack in packs {
+            match self.backend.delete(&Self::pack_key(pack)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypt and save a blob. Blobs are buffered and written out in packs
+    /// of about `PACK_TARGET_SIZE` bytes, as many packs at a time as the
+    /// backend uploads concurrently; call `flush` to write the rest. Writing
+    /// takes a shared lock if none is held through this handle.
+    /// Returns the content-derived ID; saving the same content twice is a no-op.
+    pub fn save_blob(&self, blob: &[u8]) -> io::Result<BlobId> {
+        let id = self.blob_id(blob);
+        self.save_blob_with_id(id, blob)?;
+        Ok(id)
+    }
+
+    /// Like `save_blob`, for a blob whose ID `blob_id` already computed.
+    /// Returns whether the blob was new.
+    pub(crate) fn save_blob_with_id(&self, id: BlobId, blob: &[u8]) -> io::Result<bool> {
+        let mut pending = self.pending();
+        if pending.get(&id).is_some() || self.index()?.contains(&id) {
+            return Ok(false);
+        }
+        let data = encrypt_blob(blob, &self.key.data_key, self.key.key_id(), self.compression)
+            .map_err(io::Error::other)?;
+        pending.current.add(id, &data);
+        if pending.current.len() >= self.pack_size {
+            let full = mem::take(&mut pending.current);
+            pending.full.push(full);
+            if pending.full.len() >= self.backend.concurrency() {
+                let _lock = self.write_lock()?;
+                self.write_packs(&mut pending)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Drop buffered blobs without writing them.
+    pub(crate) fn discard_pending(&self) {
+        *self.pending() = Pending::default();
+    }
+
+    /// Write out blobs that are still buffered, merging the index segments
+    /// if there are many. Takes a shared lock if none is held through this
+    /// handle.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut pending = self.pending();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let _lock = self.write_lock()?;
+        self.write_packs(&mut pending)?;
+        let mut index = self.index()?;
+        if index.segment_count() > CONSOLIDATE_AFTER {
+            index.consolidate(self.backend())?;
+        }
+        Ok(())
+    }
+
+    /// Whether a blob with this ID has been saved.
+    pub fn has_blob(&self, id: &BlobId) -> io::Result<bool> {
+        if self.pending().get(id).is_some() {
+            return Ok(true);
+        }
+        Ok(self.index()?.contains(id))
+    }
+
+    /// Read and decrypt a blob.
+    pub fn load_blob(&self, id: &BlobId) -> io::Result<Vec<u8>> {
+        let buffered = self.pending().get(id).map(<[u8]>::to_vec);
+        let data = match buffered {
+            Some(data) => data,
+            None => {
+                let mut index = self.index()?;
+                if !index.contains(id) {
+                    // Possibly saved by another process since we loaded.
+                    index.update(self.backend())?;
+                }
+                let Some(entry) = index.get(id) else {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("no blob {}", id)));
+                };
+                let key = Self::pack_key(&entry.pack);
+                let (offset, length) = (entry.offset, entry.length);
+                drop(index);
+                self.backend.get_range(&key, offset, length)?
+            }
+        };
+        self.open_blob(id, &data)
+    }
+
+    /// Decrypt the stored envelope of blob `id`.
+    pub(crate) fn open_blob(&self, id: &BlobId, envelope: &[u8]) -> io::Result<Vec<u8>> {
+        decrypt_blob(envelope, &self.key.data_key, self.key.key_id())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("blob {}: {}", id, e)))
+    }
+
+    /// Index entries for the blobs in pack `name`, read from its header.
+    pub(crate) fn read_pack_entries(&self, name: &str) -> io::Result<Vec<IndexEntry>> {
+        let entries = load_pack_header(self.backend(), &Self::pack_key(name), &self.key)?;
+        Ok(entries.into_iter().map(|e| IndexEntry::new(name, e)).collect())
+    }
+
+    /// Add entries for packs missing from the index, writing them out only
+    /// if `persist` is set.
+    pub(crate) fn add_to_index(&self, entries: Vec<IndexEntry>, persist: bool) -> io::Result<()> {
+        let mut index = self.index()?;
+        if persist {
+            index.append(self.backend(), entries)
+        } else {
+            index.add(entries);
+            Ok(())
+        }
+    }
+
+    /// Recreate the index from the headers of all pack files.
+    pub fn rebuild_index(&self) -> io::Result<()> {
+        let mut index = Vec::new();
+        for key in self.backend.list("packs")? {
+            if let Some(name) = Self::pack_name(&key) {
+                index.extend(self.read_pack_entries(name)?);
+            }
+        }
+        self.index.lock().unwrap_or_else(PoisonError::into_inner).replace_all(self.backend(), index)
+    }
+
+    /// List the IDs of all saved blobs.
+    pub fn list_blobs(&self) -> io::Result<Vec<BlobId>> {
+        let mut ids: Vec<BlobId> = self.read_index()?.into_iter().map(|e| e.id).collect();
+        ids.extend(self.pending().writers().flat_map(|w| w.entries()).map(|e| e.id));
+        Ok(ids)
+    }
 }
 
-/// Initialize the repository directory and index file.
-/// Creates directory and an empty index if missing.
-pub fn init_repo() -> io::Result<(PathBuf, PathBuf)> {
-    let (repo_dir, index_file) = get_paths()?;
-    fs::create_dir_all(&repo_dir)?;
-    if !index_file.exists() {
-        fs::write(&index_file, "[]")?;
+impl Drop for Repository {
+    /// Write out buffered blobs, under a shared lock unless one is still
+    /// held. Errors are ignored here; call `flush` to handle them.
+    fn drop(&mut self) {
+        let _ = self.flush();
     }
-    Ok((repo_dir, index_file))
 }
 
-/// Save a blob to the repository and append to the index.
-/// Returns the assigned UUID.
-pub fn save_blob(blob: &[u8]) -> io::Result<Uuid> {
-    let (repo_dir, index_file) = init_repo()?;
-    let id = Uuid::new_v4();
-    let filename = format!("{}.blob", id);
-    let blob_path = repo_dir.join(&filename);
-    fs::write(&blob_path, blob)?;
-    // Read and update index
-    let mut entries: Vec<IndexEntry> = serde_json::from_slice(&fs::read(&index_file)?)?;
-    entries.push(IndexEntry { id, filename, length: blob.len() });
-    let new_index = serde_json::to_string_pretty(&entries)?;
-    fs::write(&index_file, new_index)?;
-    Ok(id)
+/// Initialize the default repository, protected by `password`.
+pub fn init_repo(password: &str) -> io::Result<Repository> {
+    Repository::init(&default_repo_dir()?, password)
 }
 
-/// List all blob UUIDs via the repository index.
-pub fn list_blobs() -> io::Result<Vec<Uuid>> {
-    let (_repo_dir, index_file) = init_repo()?;
-    let entries: Vec<IndexEntry> = serde_json::from_slice(&fs::read(&index_file)?)?;
-    Ok(entries.into_iter().map(|e| e.id).collect())
+/// Open and unlock the default repository with `password`.
+pub fn open_repo(password: &str) -> io::Result<Repository> {
+    Repository::open(&default_repo_dir()?, password)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::ObjectInfo;
+    use std::fs;
+    use std::sync::Arc;
     use tempfile::tempdir;
 
     #[test]
     fn test_init_repo_empty() -> io::Result<()> {
         let temp = tempdir()?;
         unsafe { std::env::set_var("XDG_DATA_HOME", temp.path()); }
-        let (repo_dir, index_file) = init_repo()?;
-        assert!(repo_dir.exists());
-        assert!(index_file.exists());
-        let entries: Vec<IndexEntry> = serde_json::from_slice(&fs::read(&index_file)?)?;
-        assert!(entries.is_empty());
+        let repo = init_repo("pw")?;
+        assert!(temp.path().join("repo").join("config.json").exists());
+        assert!(repo.list_blobs()?.is_empty());
+        assert!(open_repo("pw").is_ok());
+        assert!(init_repo("pw").is_err());
         Ok(())
     }
 
     #[test]
     fn test_save_and_list_blobs() -> io::Result<()> {
         let temp = tempdir()?;
-        unsafe { std::env::set_var("XDG_DATA_HOME", temp.path()); }
+        let repo = Repository::init(temp.path(), "pw")?;
         let blob = b"hello".to_vec();
-        let id = save_blob(&blob)?;
-        let ids = list_blobs()?;
+        let id = repo.save_blob(&blob)?;
+        let ids = repo.list_blobs()?;
         assert_eq!(ids.len(), 1);
         assert_eq!(ids[0], id);
+        assert_eq!(repo.load_blob(&id)?, blob);
+        repo.flush()?;
+        assert_eq!(repo.list_blobs()?, ids);
+        assert_eq!(repo.load_blob(&id)?, blob);
+        Ok(())
+    }
+
+    fn stored_length(repo: &Repository, id: &BlobId) -> io::Result<u64> {
+        repo.flush()?;
+        Ok(repo.read_index()?.iter().find(|e| e.id == *id).unwrap().length)
+    }
+
+    #[test]
+    fn test_blobs_are_packed() -> io::Result<()> {
+        let temp = tempdir()?;
+        let mut repo = Repository::init(temp.path(), "pw")?;
+        repo.set_pack_size(1000);
+        repo.set_compression(CompressionMode::Off);
+        let blobs: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 300]).collect();
+        let ids = blobs.iter().map(|b| repo.save_blob(b)).collect::<io::Result<Vec<_>>>()?;
+        drop(repo);
+
+        let mut packs = 0;
+        for shard in fs::read_dir(temp.path().join("packs"))? {
+            packs += fs::read_dir(shard?.path())?.count();
+        }
+        assert!(packs > 1 && packs < blobs.len());
+
+        let repo = Repository::open(temp.path(), "pw")?;
+        assert_eq!(repo.list_blobs()?, ids);
+        for (id, blob) in ids.iter().zip(&blobs) {
+            assert_eq!(&repo.load_blob(id)?, blob);
+        }
+
+        fs::remove_dir_all(temp.path().join("index"))?;
+        let repo = Repository::open(temp.path(), "pw")?;
+        assert!(repo.list_blobs()?.is_empty());
+        repo.rebuild_index()?;
+        let mut rebuilt = repo.list_blobs()?;
+        let mut expected = ids.clone();
+        rebuilt.sort();
+        expected.sort();
+        assert_eq!(rebuilt, expected);
+        assert_eq!(repo.load_blob(&ids[3])?, blobs[3]);
+        Ok(())
+    }
+
+    /// Local backend that claims to upload three objects at once and
+    /// records the size of every `put_all` batch.
+    struct BatchingBackend {
+        inner: LocalBackend,
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Backend for BatchingBackend {
+        fn location(&self) -> String {
+            self.inner.location()
+        }
+        fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+            self.inner.put(key, data)
+        }
+        fn concurrency(&self) -> usize {
+            3
+        }
+        fn put_all(&self, objects: &[(String, Vec<u8>)]) -> io::Result<()> {
+            self.batches.lock().unwrap().push(objects.len());
+            objects.iter().try_for_each(|(key, data)| self.inner.put(key, data))
+        }
+        fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+            self.inner.get(key)
+        }
+        fn get_range(&self, key: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+            self.inner.get_range(key, offset, length)
+        }
+        fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+            self.inner.list(prefix)
+        }
+        fn delete(&self, key: &str) -> io::Result<()> {
+            self.inner.delete(key)
+        }
+        fn stat(&self, key: &str) -> io::Result<ObjectInfo> {
+            self.inner.stat(key)
+        }
+    }
+
+    #[test]
+    fn test_packs_are_uploaded_in_batches() -> io::Result<()> {
+        let temp = tempdir()?;
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let backend =
+            BatchingBackend { inner: LocalBackend::new(temp.path()), batches: batches.clone() };
+        let mut repo = Repository::init_with(Box::new(backend), "pw")?;
+        repo.set_pack_size(500);
+        repo.set_compression(CompressionMode::Off);
+        let blobs: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 600]).collect();
+        let ids = blobs.iter().map(|b| repo.save_blob(b)).collect::<io::Result<Vec<_>>>()?;
+        // Full packs are held back until three of them can go out together.
+        assert_eq!(*batches.lock().unwrap(), [3, 3]);
+        for (id, blob) in ids.iter().zip(&blobs) {
+            assert_eq!(&repo.load_blob(id)?, blob);
+        }
+        repo.flush()?;
+        assert_eq!(*batches.lock().unwrap(), [3, 3, 2]);
+        assert_eq!(repo.list_blobs()?, ids);
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_blob_compresses() -> io::Result<()> {
+        let temp = tempdir()?;
+        let mut repo = Repository::init(temp.path(), "pw")?;
+        let text = b"2024-01-01 INFO request served\n".repeat(5000);
+        let id = repo.save_blob(&text)?;
+        assert!(stored_length(&repo, &id)? < text.len() as u64 / 10);
+        assert_eq!(repo.load_blob(&id)?, text);
+
+        repo.set_compression(CompressionMode::Off);
+        let other = b"uncompressed ".repeat(5000);
+        let id = repo.save_blob(&other)?;
+        assert!(stored_length(&repo, &id)? > other.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_blob_deduplicates() -> io::Result<()> {
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        let first = repo.save_blob(b"same chunk")?;
+        let second = repo.save_blob(b"same chunk")?;
+        let other = repo.save_blob(b"other chunk")?;
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(repo.list_blobs()?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_with_wrong_password() -> io::Result<()> {
+        let temp = tempdir()?;
+        let id = Repository::init(temp.path(), "pw")?.save_blob(b"data")?;
+        assert!(Repository::open(temp.path(), "wrong").is_err());
+        assert_eq!(Repository::open(temp.path(), "pw")?.load_blob(&id)?, b"data");
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_password() -> io::Result<()> {
+        let temp = tempdir()?;
+        let mut repo = Repository::init(temp.path(), "old")?;
+        let id = repo.save_blob(b"data")?;
+        repo.flush()?;
+        repo.change_password("new")?;
+        assert_eq!(repo.list_keys()?.len(), 1);
+        assert!(Repository::open(temp.path(), "old").is_err());
+        assert_eq!(Repository::open(temp.path(), "new")?.load_blob(&id)?, b"data");
+        Ok(())
+    }
+
+    #[test]
+    fn test_locks_are_released_and_can_be_removed() -> io::Result<()> {
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        let other = Repository::open(temp.path(), "pw")?;
+        {
+            let _shared = repo.lock_shared()?;
+            let _also_shared = other.lock_shared()?;
+            let err = other.lock_exclusive().err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+            assert_eq!(repo.list_locks()?.len(), 2);
+        }
+        assert!(repo.list_locks()?.is_empty());
+
+        let _held = repo.lock_exclusive()?;
+        assert!(crate::backup::backup(&other, &[temp.path()], Vec::new()).is_err());
+        assert!(other.remove_locks(false)?.is_empty());
+        assert!(repo.remove_locks(true)?.is_empty());
+        assert_eq!(other.remove_locks(true)?.len(), 1);
+        assert!(other.lock_exclusive().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_segments_are_consolidated() -> io::Result<()> {
+        let temp = tempdir()?;
+        let mut repo = Repository::init(temp.path(), "pw")?;
+        repo.set_pack_size(100);
+        let segments = || fs::read_dir(temp.path().join("index")).map(|d| d.count());
+        let mut ids = Vec::new();
+        for i in 0..CONSOLIDATE_AFTER as u32 {
+            ids.push(repo.save_blob(&i.to_le_bytes())?);
+            repo.write_packs(&mut repo.pending())?;
+        }
+        assert_eq!(segments()?, CONSOLIDATE_AFTER);
+        ids.push(repo.save_blob(b"last")?);
+        repo.flush()?;
+        assert_eq!(segments()?, 1);
+
+        let reopened = Repository::open(temp.path(), "pw")?;
+        let mut listed = reopened.list_blobs()?;
+        listed.sort();
+        ids.sort();
+        assert_eq!(listed, ids);
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_2_repository_is_upgraded() -> io::Result<()> {
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        let id = repo.save_blob(b"data")?;
+        repo.flush()?;
+        let entries = repo.read_index()?;
+        fs::remove_dir_all(temp.path().join("index"))?;
+        fs::write(temp.path().join(LEGACY_INDEX), serde_json::to_vec(&entries)?)?;
+        let mut config: RepoConfig = serde_json::from_slice(&fs::read(temp.path().join(CONFIG))?)?;
+        config.version = 2;
+        fs::write(temp.path().join(CONFIG), serde_json::to_vec(&config)?)?;
+
+        assert!(Repository::open(temp.path(), "wrong").is_err());
+        assert!(temp.path().join(LEGACY_INDEX).exists());
+        let repo = Repository::open(temp.path(), "pw")?;
+        assert_eq!(repo.load_blob(&id)?, b"data");
+        assert!(!temp.path().join(LEGACY_INDEX).exists());
+        let config: RepoConfig = serde_json::from_slice(&fs::read(temp.path().join(CONFIG))?)?;
+        assert_eq!(config.version, REPO_VERSION);
+        Ok(())
+    }
+
+    #[test]
+    fn test_writes_take_a_shared_lock() -> io::Result<()> {
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        let other = Repository::open(temp.path(), "pw")?;
+        let id = repo.save_blob(b"data")?;
+        {
+            let _held = other.lock_exclusive()?;
+            let err = repo.flush().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        }
+        assert!(repo.list_locks()?.is_empty());
+        repo.flush()?;
+        assert!(repo.list_locks()?.is_empty());
+        assert_eq!(other.load_blob(&id)?, b"data");
         Ok(())
     }
 }
@@ -0,0 +1,1018 @@
+// SFTP module: SSH file transfer client and a repository backend on top of it
+
+use crate::backend::{Backend, ObjectInfo, temp_path};
+use crate::known_hosts::{HostKeyPolicy, verify_host_key};
+use serde::Deserialize;
+use ssh2::{
+    FileStat, KeyboardInteractivePrompt, OpenFlags, OpenType, Prompt, RenameFlags, Session, Sftp,
+};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+use std::fs::{self, File};
+use std::fmt;
+use std::error::Error;
+
+/// Errors returned by `SftpClient`.
+#[derive(Debug)]
+pub enum SftpError {
+    Io(io::Error),
+    Ssh(ssh2::Error),
+    Authentication(String),
+    Connection(String),
+    Operation(String),
+    /// The server's host key is not in any known_hosts file. Connect again
+    /// with `HostKeyPolicy::accept_fingerprint` set once the user confirmed it.
+    UnknownHostKey { host: String, fingerprint: String },
+    /// The server's host key differs from the recorded one.
+    HostKeyMismatch { host: String, fingerprint: String },
+}
+
+impl fmt::Display for SftpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SftpError::Io(err) => write!(f, "IO error: {}", err),
+            SftpError::Ssh(err) => write!(f, "SSH error: {}", err),
+            SftpError::Authentication(msg) => write!(f, "Authentication error: {}", msg),
+            SftpError::Connection(msg) => write!(f, "Connection error: {}", msg),
+            SftpError::Operation(msg) => write!(f, "SFTP operation error: {}", msg),
+            SftpError::UnknownHostKey { host, fingerprint } => {
+                write!(f, "Unknown host key for {}: {}", host, fingerprint)
+            }
+            SftpError::HostKeyMismatch { host, fingerprint } => write!(
+                f,
+                "Host key for {} has changed to {}; refusing to connect (possible man-in-the-middle attack)",
+                host, fingerprint
+            ),
+        }
+    }
+}
+
+impl Error for SftpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SftpError::Io(err) => Some(err),
+            SftpError::Ssh(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SftpError {
+    fn from(err: io::Error) -> Self {
+        SftpError::Io(err)
+    }
+}
+
+impl From<ssh2::Error> for SftpError {
+    fn from(err: ssh2::Error) -> Self {
+        SftpError::Ssh(err)
+    }
+}
+
+impl From<SftpError> for io::Error {
+    fn from(err: SftpError) -> Self {
+        match err {
+            SftpError::Io(err) => err,
+            SftpError::Ssh(err) => err.into(),
+            other => io::Error::other(other),
+        }
+    }
+}
+
+/// How to authenticate to the SSH server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "method", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum SftpAuth {
+    Password { password: String },
+    /// A private key file, optionally protected by a passphrase.
+    KeyFile { private_key: PathBuf, passphrase: Option<String> },
+    /// Identities offered by a running ssh-agent.
+    Agent,
+    /// Keyboard-interactive login; every prompt is answered with `response`.
+    KeyboardInteractive { response: String },
+}
+
+/// Answers every keyboard-interactive prompt with the same response.
+struct FixedResponse<'a>(&'a str);
+
+impl KeyboardInteractivePrompt for FixedResponse<'_> {
+    fn prompt<'b>(&mut self, _username: &str, _instructions: &str, prompts: &[Prompt<'b>]) -> Vec<String> {
+        prompts.iter().map(|_| self.0.to_string()).collect()
+    }
+}
+
+fn authenticate(session: &Session, username: &str, auth: &SftpAuth) -> Result<(), SftpError> {
+    let failed = |e: ssh2::Error| SftpError::Authentication(e.message().to_string());
+    match auth {
+        SftpAuth::Password { password } => {
+            session.userauth_password(username, password).map_err(failed)
+        }
+        SftpAuth::KeyFile { private_key, passphrase } => session
+            .userauth_pubkey_file(username, None, private_key, passphrase.as_deref())
+            .map_err(failed),
+        SftpAuth::Agent => {
+            let mut agent = session.agent()?;
+            agent.connect().map_err(|e| {
+                SftpError::Authentication(format!("Cannot reach ssh-agent: {}", e.message()))
+            })?;
+            agent.list_identities()?;
+            for identity in agent.identities()? {
+                if agent.userauth(username, &identity).is_ok() {
+                    return Ok(());
+                }
+            }
+            Err(SftpError::Authentication("No ssh-agent identity was accepted".to_string()))
+        }
+        SftpAuth::KeyboardInteractive { response } => session
+            .userauth_keyboard_interactive(username, &mut FixedResponse(response))
+            .map_err(failed),
+    }
+}
+
+/// Bytes handed to libssh2 per read or write call. libssh2 splits a large
+/// write into many SFTP requests that are in flight at once (and reads ahead
+/// likewise), so a bigger buffer keeps the link busy on high-latency paths.
+const TRANSFER_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Seconds between keep-alive messages on idle connections.
+pub const KEEPALIVE_INTERVAL: u32 = 30;
+
+/// An authenticated SFTP connection with its open SFTP channel.
+pub struct SftpClient {
+    session: Session,
+    sftp: Sftp,
+}
+
+impl SftpClient {
+    /// Connect to `host:port` and log in with a password, verifying the host
+    /// key with the default `HostKeyPolicy`.
+    pub fn new(host: &str, port: u16, username: &str, password: &str) -> Result<Self, SftpError> {
+        let auth = SftpAuth::Password { password: password.to_string() };
+        Self::connect(host, port, username, &auth, &HostKeyPolicy::default())
+    }
+
+    /// Connect to `host:port`, verify its host key against `host_keys`, then
+    /// log in with `auth`.
+    pub fn connect(
+        host: &str,
+        port: u16,
+        username: &str,
+        auth: &SftpAuth,
+        host_keys: &HostKeyPolicy,
+    ) -> Result<Self, SftpError> {
+        let tcp = TcpStream::connect((host, port)).map_err(|e| {
+            SftpError::Connection(format!("Failed to connect to {}:{}: {}", host, port, e))
+        })?;
+        tcp.set_read_timeout(Some(Duration::from_secs(10)))?;
+        tcp.set_write_timeout(Some(Duration::from_secs(10)))?;
+
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+        verify_host_key(&session, host, port, host_keys)?;
+        authenticate(&session, username, auth)?;
+
+        if !session.authenticated() {
+            return Err(SftpError::Authentication("Authentication failed".to_string()));
+        }
+        session.set_keepalive(true, KEEPALIVE_INTERVAL);
+        let sftp = session.sftp()?;
+        Ok(Self { session, sftp })
+    }
+
+    /// Send a keep-alive message if one is due, so that idle connections are
+    /// not dropped by the server or by firewalls in between.
+    pub fn keepalive(&self) -> Result<(), SftpError> {
+        self.session.keepalive_send()?;
+        Ok(())
+    }
+
+    /// Whether the server still answers requests on this connection.
+    pub fn is_alive(&self) -> bool {
+        self.sftp.realpath(Path::new(".")).is_ok()
+    }
+
+    /// Upload a local file to `remote_path`. The data is written to
+    /// `<remote_path>.part` and renamed into place once complete. With
+    /// `Resume::Continue` or `Resume::Verify`, a `.part` file left by an
+    /// interrupted upload is completed instead of rewritten.
+    pub fn upload_file(
+        &self,
+        local_path: &Path,
+        remote_path: &str,
+        resume: Resume,
+    ) -> Result<TransferSummary, SftpError> {
+        let mut summary = TransferSummary::default();
+        put_file(&self.sftp, local_path, Path::new(remote_path), resume, &mut summary)?;
+        Ok(summary)
+    }
+
+    /// Upload a local file or directory tree to `remote_path`, preserving
+    /// the relative structure. Symlinks and special files are skipped.
+    pub fn upload_tree(
+        &self,
+        local_path: &Path,
+        remote_path: &str,
+        resume: Resume,
+    ) -> Result<TransferSummary, SftpError> {
+        let mut summary = TransferSummary::default();
+        upload_node(&self.sftp, local_path, Path::new(remote_path), resume, &mut summary)?;
+        Ok(summary)
+    }
+
+    /// Upload a local file or directory into the remote directory
+    /// `remote_dir`, keeping its name. The directory and its parents are
+    /// created first if missing. Returns the remote path of the upload along
+    /// with transfer counters.
+    pub fn upload_into(
+        &self,
+        local_path: &Path,
+        remote_dir: &str,
+        resume: Resume,
+    ) -> Result<(String, TransferSummary), SftpError> {
+        let file_name = local_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| {
+                SftpError::Operation(format!("Invalid local file name: {}", local_path.display()))
+            })?;
+        create_dirs(&self.sftp, Path::new(remote_dir))?;
+        let remote_path = Path::new(remote_dir).join(file_name);
+        let remote_path = remote_path.to_str().ok_or_else(|| {
+            SftpError::Operation(format!("Remote path is not valid UTF-8: {}", remote_path.display()))
+        })?;
+        let mut summary = TransferSummary::default();
+        upload_node(&self.sftp, local_path, Path::new(remote_path), resume, &mut summary)?;
+        Ok((remote_path.to_string(), summary))
+    }
+
+    /// Create a remote directory and any missing parents, like `mkdir -p`.
+    /// Succeeds if the directory already exists.
+    pub fn create_directory(&self, path: &str) -> Result<(), SftpError> {
+        create_dirs(&self.sftp, Path::new(path))
+    }
+
+    /// Names of the entries of a remote directory.
+    pub fn list_directory(&self, remote_path: &str) -> Result<Vec<String>, SftpError> {
+        let path = Path::new(remote_path);
+        let entries = self.sftp.readdir(path).map_err(|e| {
+            SftpError::Operation(format!(
+                "Failed to read directory '{}': {}",
+                remote_path, e
+            ))
+        })?;
+
+        let filenames = entries
+            .into_iter()
+            .map(|(entry_path, _stat)| {
+                entry_path
+                    .file_name()
+                    .unwrap_or_default() // Use OsStr::new("") if file_name is None
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+
+        Ok(filenames)
+    }
+
+    /// Download `remote_path` to a local file. With `Resume::Continue` or
+    /// `Resume::Verify`, a partial local file left by an interrupted download
+    /// is completed instead of rewritten.
+    pub fn download_file(
+        &self,
+        remote_path: &str,
+        local_path: &Path,
+        resume: Resume,
+    ) -> Result<TransferSummary, SftpError> {
+        let mut summary = TransferSummary::default();
+        get_file(&self.sftp, Path::new(remote_path), local_path, resume, &mut summary)?;
+        Ok(summary)
+    }
+
+    /// Download a remote file or directory tree to `local_path`, preserving
+    /// the relative structure. Symlinks and special files are skipped.
+    pub fn download_tree(
+        &self,
+        remote_path: &str,
+        local_path: &Path,
+        resume: Resume,
+    ) -> Result<TransferSummary, SftpError> {
+        let remote_path = Path::new(remote_path);
+        let stat = self.sftp.stat(remote_path).map_err(|e| {
+            SftpError::Operation(format!("Failed to stat '{}': {}", remote_path.display(), e))
+        })?;
+        let mut summary = TransferSummary::default();
+        download_node(&self.sftp, remote_path, &stat, local_path, resume, &mut summary)?;
+        Ok(summary)
+    }
+}
+
+/// How a transfer treats a partial destination file left by an interrupted
+/// transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Resume {
+    /// Rewrite the destination from the first byte.
+    #[default]
+    Restart,
+    /// Continue after the bytes already present at the destination.
+    Continue,
+    /// Like `Continue`, but first compare checksums of the already
+    /// transferred prefix on both sides and restart if they differ. For
+    /// uploads, a finished destination whose contents match is skipped.
+    Verify,
+}
+
+/// Counters reported by transfers.
+#[derive(Debug, Clone, Default)]
+pub struct TransferSummary {
+    pub files: u64,
+    pub dirs: u64,
+    /// Bytes sent or received.
+    pub bytes: u64,
+    /// Bytes already present at the destination and kept when resuming.
+    pub resumed: u64,
+}
+
+/// The remote file operations transfers are built on. Implemented by `Sftp`;
+/// tests run the transfer helpers against the local filesystem instead.
+trait RemoteFs {
+    type File: Read + Write + Seek;
+
+    fn stat(&self, path: &Path) -> io::Result<FileStat>;
+    fn mkdir(&self, path: &Path) -> io::Result<()>;
+    /// Entries of a directory, without `.` and `..`.
+    fn readdir(&self, path: &Path) -> io::Result<Vec<(PathBuf, FileStat)>>;
+    fn open(&self, path: &Path) -> io::Result<Self::File>;
+    /// Open an existing file for writing without truncating it.
+    fn open_write(&self, path: &Path) -> io::Result<Self::File>;
+    /// Create a file for writing, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Self::File>;
+    /// Rename `from` to `to`, replacing `to` where the server allows it.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn unlink(&self, path: &Path) -> io::Result<()>;
+    /// Write the file's data through to disk where possible, then close it.
+    fn close_synced(&self, file: Self::File);
+}
+
+impl RemoteFs for Sftp {
+    type File = ssh2::File;
+
+    fn stat(&self, path: &Path) -> io::Result<FileStat> {
+        Ok(Sftp::stat(self, path)?)
+    }
+
+    fn mkdir(&self, path: &Path) -> io::Result<()> {
+        Ok(Sftp::mkdir(self, path, 0o755)?)
+    }
+
+    fn readdir(&self, path: &Path) -> io::Result<Vec<(PathBuf, FileStat)>> {
+        Ok(Sftp::readdir(self, path)?)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<ssh2::File> {
+        Ok(Sftp::open(self, path)?)
+    }
+
+    fn open_write(&self, path: &Path) -> io::Result<ssh2::File> {
+        Ok(self.open_mode(path, OpenFlags::WRITE, 0o644, OpenType::File)?)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<ssh2::File> {
+        Ok(Sftp::create(self, path)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+        Ok(Sftp::rename(self, from, to, flags)?)
+    }
+
+    fn unlink(&self, path: &Path) -> io::Result<()> {
+        Ok(Sftp::unlink(self, path)?)
+    }
+
+    /// Uses `fsync@openssh.com`, which not every server supports.
+    fn close_synced(&self, mut file: ssh2::File) {
+        let _ = file.fsync();
+    }
+}
+
+/// Create `path` and its missing parents on the server. Existing
+/// directories are left alone; an existing component that is not a
+/// directory is an error.
+fn create_dirs<R: RemoteFs>(sftp: &R, path: &Path) -> Result<(), SftpError> {
+    let mut missing = Vec::new();
+    let mut dir = Some(path);
+    while let Some(d) = dir {
+        if d.as_os_str().is_empty() {
+            break;
+        }
+        match sftp.stat(d) {
+            Ok(stat) if stat.is_dir() => break,
+            Ok(_) => {
+                return Err(SftpError::Operation(format!(
+                    "Cannot create remote directory '{}': '{}' exists and is not a directory",
+                    path.display(),
+                    d.display()
+                )));
+            }
+            Err(_) => missing.push(d),
+        }
+        dir = d.parent();
+    }
+    for d in missing.into_iter().rev() {
+        if let Err(e) = sftp.mkdir(d) {
+            // Another client may have created it in the meantime.
+            if !sftp.stat(d).is_ok_and(|s| s.is_dir()) {
+                return Err(SftpError::Operation(format!(
+                    "Failed to create remote directory '{}': {}",
+                    d.display(),
+                    e
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Byte offset a transfer continues from, given the length of the source
+/// and of the partial destination. A destination longer than the source
+/// cannot be a prefix of it, so the transfer restarts.
+fn resume_offset(resume: Resume, source_len: u64, dest_len: u64) -> u64 {
+    match resume {
+        Resume::Restart => 0,
+        Resume::Continue | Resume::Verify if dest_len <= source_len => dest_len,
+        Resume::Continue | Resume::Verify => 0,
+    }
+}
+
+/// Hash of the first `len` bytes of `reader`.
+fn hash_prefix<R: Read>(reader: R, len: u64) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    let copied = io::copy(&mut reader.take(len), &mut hasher)?;
+    if copied < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shorter than expected"));
+    }
+    Ok(hasher.finalize())
+}
+
+/// Whether the first `len` bytes of `a` and `b` are identical.
+fn same_prefix<A: Read + Seek, B: Read + Seek>(mut a: A, mut b: B, len: u64) -> io::Result<bool> {
+    a.seek(SeekFrom::Start(0))?;
+    b.seek(SeekFrom::Start(0))?;
+    Ok(hash_prefix(a, len)? == hash_prefix(b, len)?)
+}
+
+/// Name an upload to `path` is written under until it is complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.part", name))
+}
+
+/// Rename `from` to `to`, replacing `to`. SFTP v3 servers such as OpenSSH
+/// refuse to rename over an existing file, in which case the old file is
+/// removed first.
+fn replace_remote<R: RemoteFs>(sftp: &R, from: &Path, to: &Path) -> io::Result<()> {
+    match sftp.rename(from, to) {
+        Err(_) if sftp.stat(to).is_ok() => {
+            sftp.unlink(to)?;
+            sftp.rename(from, to)
+        }
+        result => result,
+    }
+}
+
+/// Copy one local file to the server, continuing a partial upload as
+/// requested by `resume`. Data goes to a `.part` file that is renamed to
+/// `remote_path` once complete, so the final name never holds a truncated
+/// file.
+fn put_file<R: RemoteFs>(
+    sftp: &R,
+    local_path: &Path,
+    remote_path: &Path,
+    resume: Resume,
+    summary: &mut TransferSummary,
+) -> Result<(), SftpError> {
+    let mut local_file = File::open(local_path)?;
+    let local_len = local_file.metadata()?.len();
+    let size = |path: &Path| sftp.stat(path).ok().and_then(|s| s.size);
+
+    // Completed by an earlier run that was interrupted later on. Only
+    // trusted once the contents were compared: a file that changed but kept
+    // its size must be uploaded again.
+    if resume == Resume::Verify
+        && size(remote_path) == Some(local_len)
+        && same_prefix(&mut local_file, sftp.open(remote_path)?, local_len)?
+    {
+        summary.files += 1;
+        summary.resumed += local_len;
+        return Ok(());
+    }
+
+    let part_path = partial_path(remote_path);
+    let mut offset = resume_offset(resume, local_len, size(&part_path).unwrap_or(0));
+    if resume == Resume::Verify
+        && offset > 0
+        && !same_prefix(&mut local_file, sftp.open(&part_path)?, offset)?
+    {
+        offset = 0;
+    }
+
+    let mut remote_file = if offset > 0 {
+        sftp.open_write(&part_path)
+    } else {
+        sftp.create(&part_path)
+    }
+    .map_err(|e| {
+        SftpError::Operation(format!(
+            "Failed to create remote file '{}': {}",
+            part_path.display(),
+            e
+        ))
+    })?;
+    local_file.seek(SeekFrom::Start(offset))?;
+    remote_file.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = vec![0; TRANSFER_BUFFER_SIZE];
+
+    loop {
+        let bytes_read = local_file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            // End of file
+            break;
+        }
+        remote_file.write_all(&buffer[..bytes_read]).map_err(|e| {
+            SftpError::Operation(format!(
+                "Failed to write to remote file '{}': {}",
+                part_path.display(),
+                e
+            ))
+        })?;
+        summary.bytes += bytes_read as u64;
+    }
+    remote_file.flush()?;
+    sftp.close_synced(remote_file);
+    replace_remote(sftp, &part_path, remote_path).map_err(|e| {
+        SftpError::Operation(format!(
+            "Failed to rename '{}' to '{}': {}",
+            part_path.display(),
+            remote_path.display(),
+            e
+        ))
+    })?;
+    summary.files += 1;
+    summary.resumed += offset;
+    Ok(())
+}
+
+/// Copy one remote file to the local filesystem, continuing a partial
+/// download as requested by `resume`.
+fn get_file<R: RemoteFs>(
+    sftp: &R,
+    remote_path: &Path,
+    local_path: &Path,
+    resume: Resume,
+    summary: &mut TransferSummary,
+) -> Result<(), SftpError> {
+    let mut remote_file = sftp.open(remote_path).map_err(|e| {
+        SftpError::Operation(format!(
+            "Failed to open remote file '{}': {}",
+            remote_path.display(),
+            e
+        ))
+    })?;
+    let remote_len = remote_file.seek(SeekFrom::End(0))?;
+    let local_len = fs::metadata(local_path).map(|m| m.len()).unwrap_or(0);
+    let mut offset = resume_offset(resume, remote_len, local_len);
+    if resume == Resume::Verify
+        && offset > 0
+        && !same_prefix(&mut remote_file, File::open(local_path)?, offset)?
+    {
+        offset = 0;
+    }
+
+    let mut local_file = if offset > 0 {
+        fs::OpenOptions::new().write(true).open(local_path)?
+    } else {
+        File::create(local_path)?
+    };
+    local_file.seek(SeekFrom::Start(offset))?;
+    remote_file.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = vec![0; TRANSFER_BUFFER_SIZE];
+
+    loop {
+        let n = remote_file.read(&mut buffer).map_err(|e| {
+            SftpError::Operation(format!(
+                "Failed to read from remote file '{}': {}",
+                remote_path.display(),
+                e
+            ))
+        })?;
+        if n == 0 {
+            // End of file
+            break;
+        }
+        local_file.write_all(&buffer[..n])?;
+        summary.bytes += n as u64;
+    }
+    summary.files += 1;
+    summary.resumed += offset;
+    Ok(())
+}
+
+fn upload_node<R: RemoteFs>(
+    sftp: &R,
+    local_path: &Path,
+    remote_path: &Path,
+    resume: Resume,
+    summary: &mut TransferSummary,
+) -> Result<(), SftpError> {
+    let meta = fs::symlink_metadata(local_path)?;
+    if meta.is_dir() {
+        create_dirs(sftp, remote_path)?;
+        summary.dirs += 1;
+        let mut entries = fs::read_dir(local_path)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let remote_entry = remote_path.join(entry.file_name());
+            upload_node(sftp, &entry.path(), &remote_entry, resume, summary)?;
+        }
+    } else if meta.is_file() {
+        put_file(sftp, local_path, remote_path, resume, summary)?;
+    }
+    Ok(())
+}
+
+fn download_node<R: RemoteFs>(
+    sftp: &R,
+    remote_path: &Path,
+    stat: &FileStat,
+    local_path: &Path,
+    resume: Resume,
+    summary: &mut TransferSummary,
+) -> Result<(), SftpError> {
+    if stat.is_dir() {
+        fs::create_dir_all(local_path)?;
+        summary.dirs += 1;
+        let entries = sftp.readdir(remote_path).map_err(|e| {
+            SftpError::Operation(format!(
+                "Failed to read directory '{}': {}",
+                remote_path.display(),
+                e
+            ))
+        })?;
+        for (entry_path, entry_stat) in entries {
+            let Some(name) = entry_path.file_name() else { continue };
+            let local_entry = local_path.join(name);
+            download_node(sftp, &entry_path, &entry_stat, &local_entry, resume, summary)?;
+        }
+    } else if stat.is_file() {
+        get_file(sftp, remote_path, local_path, resume, summary)?;
+    }
+    Ok(())
+}
+
+/// Repository backend storing objects below a directory on an SFTP server.
+///
+/// Objects passed to `put_all` are uploaded in parallel, one per connection;
+/// add connections with `add_stream` to raise the concurrency.
+pub struct SftpBackend {
+    clients: Vec<Arc<SftpClient>>,
+    root: PathBuf,
+}
+
+impl SftpBackend {
+    pub fn new(client: Arc<SftpClient>, root: &str) -> Self {
+        Self { clients: vec![client], root: PathBuf::from(root) }
+    }
+
+    /// Use another connection to the same server for parallel uploads.
+    pub fn add_stream(&mut self, client: Arc<SftpClient>) {
+        self.clients.push(client);
+    }
+
+    fn sftp(&self) -> &Sftp {
+        &self.clients[0].sftp
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        key.split('/').fold(self.root.clone(), |path, part| path.join(part))
+    }
+
+    /// Store an object through the SFTP channel `sftp`.
+    fn put_via(&self, sftp: &Sftp, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            create_dirs(sftp, parent)?;
+        }
+        let temp = temp_path(&path);
+        let result = sftp
+            .create(&temp)
+            .map_err(io::Error::from)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.flush()?;
+                RemoteFs::close_synced(sftp, file);
+                replace_remote(sftp, &temp, &path)
+            });
+        if result.is_err() {
+            let _ = sftp.unlink(&temp);
+        }
+        result
+    }
+
+    fn walk(&self, sftp: &Sftp, dir: &Path, prefix: &str, keys: &mut Vec<String>) -> io::Result<()> {
+        for (path, stat) in sftp.readdir(dir)? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let key = format!("{}/{}", prefix, name);
+            if stat.is_dir() {
+                self.walk(sftp, &path, &key, keys)?;
+            } else {
+                keys.push(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for SftpBackend {
+    fn location(&self) -> String {
+        format!("sftp:{}", self.root.display())
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.put_via(self.sftp(), key, data)
+    }
+
+    fn concurrency(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Upload the objects in parallel, each connection taking the next
+    /// object in turn.
+    fn put_all(&self, objects: &[(String, Vec<u8>)]) -> io::Result<()> {
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let workers: Vec<_> = self
+                .clients
+                .iter()
+                .take(objects.len())
+                .map(|client| {
+                    let next = &next;
+                    scope.spawn(move || -> io::Result<()> {
+                        while let Some((key, data)) = objects.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            self.put_via(&client.sftp, key, data)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| {
+                worker.join().unwrap_or_else(|_| Err(io::Error::other("upload thread panicked")))
+            })
+        })
+    }
+
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.sftp().open(self.path(key))?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut file = self.sftp().open(self.path(key))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; length as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let sftp = self.sftp();
+        let prefix = prefix.trim_end_matches('/');
+        let dir = self.path(prefix);
+        let mut keys = Vec::new();
+        if sftp.stat(&dir).is_ok_and(|s| s.is_dir()) {
+            self.walk(sftp, &dir, prefix, &mut keys)?;
+        }
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        Ok(self.sftp().unlink(&self.path(key))?)
+    }
+
+    fn stat(&self, key: &str) -> io::Result<ObjectInfo> {
+        let stat = self.sftp().stat(&self.path(key))?;
+        Ok(ObjectInfo { size: stat.size.unwrap_or(0) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tempfile::tempdir;
+
+    /// The local filesystem standing in for a server. Like OpenSSH, it
+    /// refuses to rename over an existing file.
+    struct LocalFs;
+
+    fn file_stat(meta: fs::Metadata) -> FileStat {
+        let kind = match () {
+            _ if meta.is_dir() => 0o040000,
+            _ if meta.is_file() => 0o100000,
+            _ => 0o120000,
+        };
+        FileStat {
+            size: Some(meta.len()),
+            uid: None,
+            gid: None,
+            perm: Some(kind | 0o644),
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    impl RemoteFs for LocalFs {
+        type File = File;
+
+        fn stat(&self, path: &Path) -> io::Result<FileStat> {
+            Ok(file_stat(fs::metadata(path)?))
+        }
+
+        fn mkdir(&self, path: &Path) -> io::Result<()> {
+            fs::create_dir(path)
+        }
+
+        fn readdir(&self, path: &Path) -> io::Result<Vec<(PathBuf, FileStat)>> {
+            fs::read_dir(path)?
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok((entry.path(), file_stat(fs::symlink_metadata(entry.path())?)))
+                })
+                .collect()
+        }
+
+        fn open(&self, path: &Path) -> io::Result<File> {
+            File::open(path)
+        }
+
+        fn open_write(&self, path: &Path) -> io::Result<File> {
+            fs::OpenOptions::new().write(true).open(path)
+        }
+
+        fn create(&self, path: &Path) -> io::Result<File> {
+            File::create(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            if to.exists() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "target exists"));
+            }
+            fs::rename(from, to)
+        }
+
+        fn unlink(&self, path: &Path) -> io::Result<()> {
+            fs::remove_file(path)
+        }
+
+        fn close_synced(&self, file: File) {
+            let _ = file.sync_all();
+        }
+    }
+
+    /// Relative paths below a directory mapped to file contents, `None` for
+    /// directories.
+    type Tree = BTreeMap<PathBuf, Option<Vec<u8>>>;
+
+    fn read_tree(root: &Path) -> io::Result<Tree> {
+        fn walk(root: &Path, dir: &Path, tree: &mut Tree) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let relative = path.strip_prefix(root).unwrap().to_path_buf();
+                if path.is_dir() {
+                    tree.insert(relative, None);
+                    walk(root, &path, tree)?;
+                } else {
+                    tree.insert(relative, Some(fs::read(&path)?));
+                }
+            }
+            Ok(())
+        }
+        let mut tree = BTreeMap::new();
+        walk(root, root, &mut tree)?;
+        Ok(tree)
+    }
+
+    #[test]
+    fn test_create_dirs() -> Result<(), SftpError> {
+        let temp = tempdir()?;
+        fs::create_dir(temp.path().join("a"))?;
+        let nested = temp.path().join("a").join("b").join("c");
+        create_dirs(&LocalFs, &nested)?;
+        assert!(nested.is_dir());
+        // Existing directories are left alone.
+        fs::write(nested.join("keep"), b"data")?;
+        create_dirs(&LocalFs, &nested)?;
+        create_dirs(&LocalFs, &temp.path().join("a"))?;
+        assert_eq!(fs::read(nested.join("keep"))?, b"data");
+
+        fs::write(temp.path().join("file"), b"")?;
+        let result = create_dirs(&LocalFs, &temp.path().join("file").join("d"));
+        assert!(matches!(result, Err(SftpError::Operation(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_upload_and_download_tree() -> Result<(), SftpError> {
+        let temp = tempdir()?;
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("sub").join("deeper"))?;
+        fs::create_dir(source.join("empty"))?;
+        fs::write(source.join("a.txt"), b"hello")?;
+        fs::write(source.join("sub").join("b.bin"), vec![7u8; 3 * TRANSFER_BUFFER_SIZE + 1])?;
+        fs::write(source.join("sub").join("deeper").join("c"), b"")?;
+        let total = (5 + 3 * TRANSFER_BUFFER_SIZE + 1) as u64;
+
+        // Parents of the destination are created as needed.
+        let remote = temp.path().join("remote").join("backups").join("source");
+        let mut summary = TransferSummary::default();
+        upload_node(&LocalFs, &source, &remote, Resume::Restart, &mut summary)?;
+        assert_eq!((summary.files, summary.dirs, summary.bytes), (3, 4, total));
+        assert_eq!(read_tree(&remote)?, read_tree(&source)?);
+
+        // Uploading again over the finished tree replaces or skips files.
+        fs::write(source.join("a.txt"), b"HELLO")?;
+        let mut summary = TransferSummary::default();
+        upload_node(&LocalFs, &source, &remote, Resume::Verify, &mut summary)?;
+        assert_eq!((summary.files, summary.bytes, summary.resumed), (3, 5, total - 5));
+        assert_eq!(read_tree(&remote)?, read_tree(&source)?);
+
+        let restored = temp.path().join("restored");
+        let stat = LocalFs.stat(&remote)?;
+        let mut summary = TransferSummary::default();
+        download_node(&LocalFs, &remote, &stat, &restored, Resume::Restart, &mut summary)?;
+        assert_eq!((summary.files, summary.dirs, summary.bytes), (3, 4, total));
+        assert_eq!(read_tree(&restored)?, read_tree(&source)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_auth_from_json() {
+        let auth: SftpAuth = serde_json::from_str(
+            r#"{"method": "keyFile", "privateKey": "/home/me/.ssh/id_ed25519", "passphrase": null}"#,
+        )
+        .unwrap();
+        assert!(matches!(auth, SftpAuth::KeyFile { passphrase: None, .. }));
+        let auth: SftpAuth = serde_json::from_str(r#"{"method": "agent"}"#).unwrap();
+        assert!(matches!(auth, SftpAuth::Agent));
+        let auth: SftpAuth =
+            serde_json::from_str(r#"{"method": "keyboardInteractive", "response": "123456"}"#).unwrap();
+        assert!(matches!(auth, SftpAuth::KeyboardInteractive { .. }));
+    }
+
+    #[test]
+    fn test_error_into_io_error() {
+        let err: io::Error = SftpError::Io(io::Error::new(io::ErrorKind::NotFound, "gone")).into();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err: io::Error = SftpError::Authentication("denied".into()).into();
+        assert_eq!(err.to_string(), "Authentication error: denied");
+    }
+
+    #[test]
+    fn test_resume_offset() {
+        assert_eq!(resume_offset(Resume::Restart, 100, 40), 0);
+        assert_eq!(resume_offset(Resume::Continue, 100, 40), 40);
+        assert_eq!(resume_offset(Resume::Verify, 100, 100), 100);
+        assert_eq!(resume_offset(Resume::Continue, 100, 120), 0);
+        let resume: Resume = serde_json::from_str(r#""verify""#).unwrap();
+        assert_eq!(resume, Resume::Verify);
+    }
+
+    #[test]
+    fn test_partial_path() {
+        assert_eq!(partial_path(Path::new("/srv/backup/a.tar")), Path::new("/srv/backup/a.tar.part"));
+    }
+
+    #[test]
+    fn test_hash_prefix() -> io::Result<()> {
+        let data = b"0123456789";
+        assert_eq!(hash_prefix(&data[..], 4)?, blake3::hash(b"0123"));
+        assert_eq!(hash_prefix(&data[..], 0)?, blake3::hash(b""));
+        assert!(hash_prefix(&data[..], 11).is_err());
+        let a = io::Cursor::new(b"0123456789".to_vec());
+        let b = io::Cursor::new(b"0123xx".to_vec());
+        assert!(same_prefix(a.clone(), b.clone(), 4)?);
+        assert!(!same_prefix(a, b, 5)?);
+        Ok(())
+    }
+}
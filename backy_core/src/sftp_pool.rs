@@ -0,0 +1,124 @@
+// SFTP pool module: reuse authenticated SFTP connections across operations
+//
+// Connecting costs a TCP connect, an SSH handshake and authentication, so
+// long-lived callers such as the GUI keep one connection per server and
+// user. Pooled connections are checked before being handed out and replaced
+// transparently when the server dropped them, or when they were opened with
+// other credentials or another host key policy.
+
+use crate::known_hosts::HostKeyPolicy;
+use crate::sftp::{SftpAuth, SftpClient, SftpError};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    host: String,
+    port: u16,
+    username: String,
+}
+
+impl PoolKey {
+    fn new(host: &str, port: u16, username: &str) -> Self {
+        PoolKey { host: host.to_string(), port, username: username.to_string() }
+    }
+}
+
+/// A pooled connection with the credentials and host key policy it was
+/// opened with.
+struct Pooled {
+    client: Arc<SftpClient>,
+    auth: SftpAuth,
+    host_keys: HostKeyPolicy,
+}
+
+/// Open SFTP connections keyed by server and user.
+///
+/// A pooled connection is only reused by callers passing the same
+/// credentials and host key policy; otherwise it is replaced by a new one.
+#[derive(Default)]
+pub struct SftpPool {
+    clients: Mutex<HashMap<PoolKey, Pooled>>,
+}
+
+impl SftpPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn clients(&self) -> MutexGuard<'_, HashMap<PoolKey, Pooled>> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Connection to `username@host:port`, reusing a pooled one if it was
+    /// opened with `auth` and `host_keys` and still answers, and connecting
+    /// again otherwise.
+    pub fn get(
+        &self,
+        host: &str,
+        port: u16,
+        username: &str,
+        auth: &SftpAuth,
+        host_keys: &HostKeyPolicy,
+    ) -> Result<Arc<SftpClient>, SftpError> {
+        let key = PoolKey::new(host, port, username);
+        let pooled = self
+            .clients()
+            .get(&key)
+            .filter(|p| p.auth == *auth && p.host_keys == *host_keys)
+            .map(|p| Arc::clone(&p.client));
+        if let Some(client) = pooled.filter(|c| c.is_alive()) {
+            return Ok(client);
+        }
+        let client = Arc::new(SftpClient::connect(host, port, username, auth, host_keys)?);
+        let pooled = Pooled {
+            client: Arc::clone(&client),
+            auth: auth.clone(),
+            host_keys: host_keys.clone(),
+        };
+        self.clients().insert(key, pooled);
+        Ok(client)
+    }
+
+    /// Send keep-alives on all pooled connections and drop those that fail.
+    /// Meant to be called periodically, e.g. every `KEEPALIVE_INTERVAL`
+    /// seconds.
+    pub fn keepalive(&self) {
+        self.clients().retain(|_, pooled| pooled.client.keepalive().is_ok());
+    }
+
+    /// Close the pooled connection to `username@host:port`, if any. Users of
+    /// the connection that still hold it keep it open until they finish.
+    pub fn disconnect(&self, host: &str, port: u16, username: &str) {
+        self.clients().remove(&PoolKey::new(host, port, username));
+    }
+
+    /// Number of pooled connections.
+    pub fn len(&self) -> usize {
+        self.clients().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_failed_connection_is_not_pooled() -> Result<(), SftpError> {
+        // Reserve a port, then close it so connecting is refused.
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let pool = SftpPool::new();
+        let auth = SftpAuth::Agent;
+        let result = pool.get("127.0.0.1", port, "backy", &auth, &HostKeyPolicy::default());
+        assert!(matches!(result, Err(SftpError::Connection(_))));
+        assert!(pool.is_empty());
+        pool.keepalive();
+        pool.disconnect("127.0.0.1", port, "backy");
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+// Snapshot module: snapshot manifests and the trees of files they reference
+
+use crate::blob_id::BlobId;
+use crate::repository::Repository;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use uuid::Uuid;
+
+/// A point-in-time backup of one or more source paths.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: Uuid,
+    pub time: DateTime<Utc>,
+    pub hostname: String,
+    pub paths: Vec<String>,
+    pub tags: Vec<String>,
+    /// ID of the root tree.
+    pub tree: BlobId,
+}
+
+impl Snapshot {
+    /// Create a snapshot of `paths` taken now on this host.
+    pub fn new(paths: Vec<String>, tags: Vec<String>, tree: BlobId) -> Self {
+        Snapshot {
+            id: Uuid::new_v4(),
+            time: Utc::now(),
+            hostname: local_hostname(),
+            paths,
+            tags,
+            tree,
+        }
+    }
+}
+
+/// Name of this machine, or an empty string if it cannot be determined.
+pub(crate) fn local_hostname() -> String {
+    hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// A directory listing: entries sorted by name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tree {
+    pub nodes: Vec<Node>,
+}
+
+/// A named entry in a tree.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: NodeKind,
+}
+
+/// What a tree entry is.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NodeKind {
+    /// A regular file made of the listed chunks, in order.
+    File { size: u64, chunks: Vec<BlobId> },
+    /// A subdirectory, stored as its own tree.
+    Dir { subtree: BlobId },
+}
+
+/// Store a tree as a blob and return its content-derived ID.
+pub fn save_tree(repo: &Repository, tree: &Tree) -> io::Result<BlobId> {
+    repo.save_blob(&serde_json::to_vec(tree)?)
+}
+
+/// Load a tree by ID.
+pub fn load_tree(repo: &Repository, id: &BlobId) -> io::Result<Tree> {
+    Ok(serde_json::from_slice(&repo.load_blob(id)?)?)
+}
+
+/// Store a snapshot manifest under `snapshots/`.
+/// Buffered blobs are flushed first so the snapshot never references blobs
+/// that are not on disk.
+pub fn save_snapshot(repo: &Repository, snapshot: &Snapshot) -> io::Result<()> {
+    repo.flush()?;
+    let key = format!("snapshots/{}.json", snapshot.id);
+    repo.backend().put(&key, &serde_json::to_vec_pretty(snapshot)?)
+}
+
+/// Delete a snapshot manifest. The data it references stays in the
+/// repository until `prune` removes what no other snapshot uses.
+pub fn remove_snapshot(repo: &Repository, id: &Uuid) -> io::Result<()> {
+    repo.backend().delete(&format!("snapshots/{}.json", id))
+}
+
+/// Load a snapshot manifest by ID.
+pub fn load_snapshot(repo: &Repository, id: &Uuid) -> io::Result<Snapshot> {
+    let data = repo.backend().get(&format!("snapshots/{}.json", id))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// List all snapshots in the repository, oldest first.
+pub fn list_snapshots(repo: &Repository) -> io::Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    for key in repo.backend().list("snapshots")? {
+        if key.ends_with(".json") {
+            snapshots.push(serde_json::from_slice::<Snapshot>(&repo.backend().get(&key)?)?);
+        }
+    }
+    snapshots.sort_by_key(|s| s.time);
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_tree_roundtrip_and_dedup() -> io::Result<()> {
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        let chunk = BlobId::from([3u8; 32]);
+        let tree = Tree {
+            nodes: vec![Node {
+                name: "a.txt".into(),
+                kind: NodeKind::File { size: 5, chunks: vec![chunk] },
+            }],
+        };
+        let id = save_tree(&repo, &tree)?;
+        assert_eq!(save_tree(&repo, &tree)?, id);
+        assert_eq!(load_tree(&repo, &id)?, tree);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_save_and_list() -> io::Result<()> {
+        let temp = tempdir()?;
+        let repo = Repository::init(temp.path(), "pw")?;
+        let tree = save_tree(&repo, &Tree::default())?;
+        let snap = Snapshot::new(vec!["/home".into()], vec!["daily".into()], tree);
+        save_snapshot(&repo, &snap)?;
+        let loaded = load_snapshot(&repo, &snap.id)?;
+        assert_eq!(loaded.tree, tree);
+        assert_eq!(loaded.tags, vec!["daily".to_string()]);
+        let all = list_snapshots(&repo)?;
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].id, snap.id);
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
 // Local storage destination: write blobs to a directory on the filesystem.
 
-use std::fs;
-use std::io;
+use crate::backend::{Backend, ObjectInfo, temp_path};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
 /// Save a blob to the given directory with the specified filename.
@@ -19,10 +20,103 @@ pub fn save_blob_local(
     if path.extension().is_none() {
         path.set_extension("blob");
     }
-    fs::write(&path, blob)?;
+    write_atomic(&path, blob)?;
     Ok(path.to_string_lossy().into_owned())
 }
 
+/// Write `data` to a temporary file next to `path` and rename it into place
+/// once synced, so readers never observe a partially written file.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Backend storing objects as files under a local directory.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: &Path) -> Self {
+        LocalBackend { root: root.to_path_buf() }
+    }
+
+    /// Directory holding the repository.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        key.split('/').fold(self.root.clone(), |path, part| path.join(part))
+    }
+
+    fn walk(&self, dir: &Path, prefix: &str, keys: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let key = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                self.walk(&entry.path(), &key, keys)?;
+            } else {
+                keys.push(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for LocalBackend {
+    fn location(&self) -> String {
+        self.root.to_string_lossy().into_owned()
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&path, data)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key))
+    }
+
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(key))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; length as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let prefix = prefix.trim_end_matches('/');
+        let dir = self.path(prefix);
+        let mut keys = Vec::new();
+        if dir.is_dir() {
+            self.walk(&dir, prefix, &mut keys)?;
+        }
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        fs::remove_file(self.path(key))
+    }
+
+    fn stat(&self, key: &str) -> io::Result<ObjectInfo> {
+        Ok(ObjectInfo { size: fs::metadata(self.path(key))?.len() })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -37,6 +131,26 @@ mod tests {
         assert!(saved.ends_with("data123.blob"));
         let loaded = fs::read(saved)?;
         assert_eq!(loaded, blob);
+        save_blob_local(b"replaced", dir.path().to_str().unwrap(), filename)?;
+        assert_eq!(fs::read(dir.path().join("data123.blob"))?, b"replaced");
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_backend() -> io::Result<()> {
+        let dir = tempdir()?;
+        let backend = LocalBackend::new(dir.path());
+        backend.put("packs/ab/abc.pack", b"0123456789")?;
+        backend.put("config.json", b"{}")?;
+        assert!(dir.path().join("packs/ab/abc.pack").exists());
+        assert_eq!(backend.get_range("packs/ab/abc.pack", 3, 4)?, b"3456");
+        assert_eq!(backend.stat("packs/ab/abc.pack")?, ObjectInfo { size: 10 });
+        assert_eq!(backend.list("packs")?, ["packs/ab/abc.pack"]);
+        assert!(backend.list("snapshots")?.is_empty());
+        backend.delete("packs/ab/abc.pack")?;
+        assert!(!backend.exists("packs/ab/abc.pack")?);
+        assert_eq!(backend.get("config.json")?, b"{}");
         Ok(())
     }
 }
@@ -12,6 +12,7 @@ function App() {
   const [dest, setDest] = useState<string>('');
   const [output, setOutput] = useState<string>('');
   const [chunkCount, setChunkCount] = useState<number | null>(null);
+  const [repoPassword, setRepoPassword] = useState<string>('');
 
   const [loading, setLoading]     = useState<boolean>(false);
   const [progress, setProgress]   = useState<number>(0);
@@ -21,24 +22,144 @@ function App() {
   const [sftpPort,        setSftpPort]        = useState<number>(22);
   const [sftpUsername,    setSftpUsername]    = useState<string>('');
   const [sftpPassword,    setSftpPassword]    = useState<string>('');
+  const [sftpAuthMethod,  setSftpAuthMethod]  = useState<SftpAuthMethod>('password');
+  const [sftpKeyPath,     setSftpKeyPath]     = useState<string>('');
+  const [sftpPassphrase,  setSftpPassphrase]  = useState<string>('');
   const [sftpRemotePath,  setSftpRemotePath]  = useState<string>('');
+  const [sftpResume,      setSftpResume]      = useState<SftpResume>('restart');
+  const [sftpStreams,     setSftpStreams]     = useState<number>(4);
+
+  /* Retention policy */
+  const [keepDaily,   setKeepDaily]   = useState<number>(7);
+  const [keepWeekly,  setKeepWeekly]  = useState<number>(4);
+  const [keepMonthly, setKeepMonthly] = useState<number>(12);
+  const [keepYearly,  setKeepYearly]  = useState<number>(2);
+  const [keepWithin,  setKeepWithin]  = useState<string>('');
 
   /* ======== Types ======== */
-  interface SaveBlobLocalArgs {
-    path: string;
+  type SftpAuthMethod = 'password' | 'keyFile' | 'agent' | 'keyboardInteractive';
+
+  type SftpResume = 'restart' | 'continue' | 'verify';
+
+  type SftpAuth =
+    | { method: 'password'; password: string }
+    | { method: 'keyFile'; privateKey: string; passphrase: string | null }
+    | { method: 'agent' }
+    | { method: 'keyboardInteractive'; response: string };
+
+  interface BackupLocalArgs {
+    source: string;
     destDir: string;
+    password: string;
   }
 
-  interface SftpBackupArgs {
+  type CheckProblem =
+    | { kind: 'missingPack'; pack: string; blobs: number }
+    | { kind: 'blobOutOfRange'; id: string; pack: string; offset: number; length: number; packSize: number }
+    | { kind: 'orphanedPack'; pack: string }
+    | { kind: 'corruptBlob'; id: string; pack: string; error: string };
+
+  interface CheckReport {
+    packs: number;
+    blobs: number;
+    blobsRead: number;
+    problems: CheckProblem[];
+  }
+
+  interface SnapshotDecision {
+    snapshot: { id: string; time: string; hostname: string; paths: string[]; tags: string[] };
+    reasons: string[];
+  }
+
+  interface ForgetGroup {
+    hostname: string;
+    paths: string[];
+    snapshots: SnapshotDecision[];
+  }
+
+  interface RepoLockInfo {
+    id: string;
+    exclusive: boolean;
+    hostname: string;
+    pid: number;
+    time: string;
+  }
+
+  interface BackupSftpArgs {
+    source: string;
     host: string;
     port: number;
     username: string;
+    auth: SftpAuth;
+    trustedFingerprint?: string;
+    remotePath: string;
     password: string;
+    streams: number;
+  }
+
+  type SftpCommandError =
+    | { kind: 'unknownHostKey'; host: string; fingerprint: string; message: string }
+    | { kind: 'other'; message: string };
+
+  interface SftpBackupArgs {
+    host: string;
+    port: number;
+    username: string;
+    auth: SftpAuth;
+    trustedFingerprint?: string;
     localPath: string;
     remotePath: string;
+    resume: SftpResume;
   }
 
   /* ======== Helpers ======== */
+  const sftpAuth = (): SftpAuth => {
+    switch (sftpAuthMethod) {
+      case 'keyFile':
+        return { method: 'keyFile', privateKey: sftpKeyPath, passphrase: sftpPassphrase || null };
+      case 'agent':
+        return { method: 'agent' };
+      case 'keyboardInteractive':
+        return { method: 'keyboardInteractive', response: sftpPassword };
+      default:
+        return { method: 'password', password: sftpPassword };
+    }
+  };
+
+  /** Message of an error thrown by an SFTP command. */
+  const sftpErrorMessage = (err: unknown): string =>
+    (err as SftpCommandError | null)?.message ?? String(err);
+
+  /**
+   * Invoke an SFTP command. If the server's host key is unknown, show its
+   * fingerprint and retry once the user has confirmed it (trust on first use).
+   */
+  const invokeSftp = async <T,>(cmd: string, args: { trustedFingerprint?: string }): Promise<T> => {
+    try {
+      return await invoke<T>(cmd, { args });
+    } catch (err) {
+      const error = err as SftpCommandError | null;
+      if (error?.kind !== 'unknownHostKey') throw sftpErrorMessage(err);
+      const trusted = window.confirm(
+        `L'hôte ${error.host} est inconnu.\nEmpreinte de sa clé : ${error.fingerprint}\n\n` +
+        'Faire confiance à cet hôte et continuer ?'
+      );
+      if (!trusted) throw error.message;
+      try {
+        return await invoke<T>(cmd, { args: { ...args, trustedFingerprint: error.fingerprint } });
+      } catch (retryErr) {
+        throw sftpErrorMessage(retryErr);
+      }
+    }
+  };
+
+  const sftpFormComplete = (): boolean => {
+    if (!sftpHost || !sftpUsername || !sftpRemotePath) return false;
+    if (sftpAuthMethod === 'keyFile') return !!sftpKeyPath;
+    if (sftpAuthMethod === 'agent') return true;
+    return !!sftpPassword;
+  };
+
   const fakeProgress = () => {
     setProgress(0);
     const id = setInterval(() => {
@@ -59,14 +180,18 @@ function App() {
       setOutput('Veuillez spécifier un chemin source ET une destination locale.');
       return;
     }
+    if (!repoPassword) {
+      setOutput('Veuillez saisir le mot de passe du dépôt.');
+      return;
+    }
     setLoading(true);
     setOutput('');
     fakeProgress();
 
-    const args: SaveBlobLocalArgs = { path: source, destDir: dest };
+    const args: BackupLocalArgs = { source, destDir: dest, password: repoPassword };
 
     try {
-      const res: string = await invoke('save_blob_local', { args });
+      const res: string = await invoke('backup_local_cmd', { args });
       setOutput(`Sauvegarde locale réussie : ${res}`);
     } catch (err) {
       setOutput(`Erreur : ${String(err)}`);
@@ -80,7 +205,7 @@ function App() {
       setOutput('Veuillez sélectionner un fichier ou dossier source.');
       return;
     }
-    if (!sftpHost || !sftpUsername || !sftpPassword || !sftpRemotePath) {
+    if (!sftpFormComplete()) {
       setOutput('Veuillez remplir tous les champs SFTP.');
       return;
     }
@@ -92,13 +217,14 @@ function App() {
       host: sftpHost,
       port: sftpPort,
       username: sftpUsername,
-      password: sftpPassword,
+      auth: sftpAuth(),
       localPath: source,
       remotePath: sftpRemotePath,
+      resume: sftpResume,
     };
 
     try {
-      const res = await invoke('sftp_backup', { args: sftpArgs });
+      const res = await invokeSftp<string>('sftp_backup', sftpArgs);
       setOutput(String(res));
     } catch (err) {
       setOutput(`Erreur : ${String(err)}`);
@@ -107,6 +233,177 @@ function App() {
     }
   };
 
+  const handleSftpRepoBackup = async () => {
+    if (!source) {
+      setOutput('Veuillez sélectionner un fichier ou dossier source.');
+      return;
+    }
+    if (!sftpFormComplete()) {
+      setOutput('Veuillez remplir tous les champs SFTP.');
+      return;
+    }
+    if (!repoPassword) {
+      setOutput('Veuillez saisir le mot de passe du dépôt.');
+      return;
+    }
+    setLoading(true);
+    setOutput('');
+    fakeProgress();
+
+    const args: BackupSftpArgs = {
+      source,
+      host: sftpHost,
+      port: sftpPort,
+      username: sftpUsername,
+      auth: sftpAuth(),
+      remotePath: sftpRemotePath,
+      password: repoPassword,
+      streams: sftpStreams,
+    };
+
+    try {
+      const res = await invokeSftp<string>('backup_sftp_cmd', args);
+      setOutput(`Sauvegarde SFTP chiffrée réussie : ${res}`);
+    } catch (err) {
+      setOutput(`Erreur : ${String(err)}`);
+    } finally {
+      setLoading(false);
+    }
+  };
+
+  const describeProblem = (p: CheckProblem): string => {
+    switch (p.kind) {
+      case 'missingPack':
+        return `Pack manquant ${p.pack} (${p.blobs} bloc(s))`;
+      case 'blobOutOfRange':
+        return `Bloc ${p.id} hors du pack ${p.pack} (${p.offset}+${p.length} > ${p.packSize})`;
+      case 'orphanedPack':
+        return `Pack orphelin ${p.pack}`;
+      case 'corruptBlob':
+        return `Bloc corrompu ${p.id} dans ${p.pack} : ${p.error}`;
+    }
+  };
+
+  const handleCheck = async () => {
+    if (!dest || !repoPassword) {
+      setOutput('Veuillez spécifier le dépôt local et son mot de passe.');
+      return;
+    }
+    setLoading(true);
+    setOutput('');
+
+    try {
+      const report = await invoke<CheckReport>('check_cmd', {
+        args: { repoDir: dest, password: repoPassword, options: { readDataPercent: 10 } },
+      });
+      const lines = [
+        `${report.packs} pack(s), ${report.blobs} bloc(s), ${report.blobsRead} bloc(s) relu(s)`,
+        report.problems.length === 0 ? 'Aucun problème trouvé.' : `${report.problems.length} problème(s) :`,
+        ...report.problems.map(describeProblem),
+      ];
+      setOutput(lines.join('\n'));
+    } catch (err) {
+      setOutput(`Erreur : ${String(err)}`);
+    } finally {
+      setLoading(false);
+    }
+  };
+
+  const handlePrune = async () => {
+    if (!dest || !repoPassword) {
+      setOutput('Veuillez spécifier le dépôt local et son mot de passe.');
+      return;
+    }
+    setLoading(true);
+    setOutput('');
+
+    const args = { repoDir: dest, password: repoPassword };
+    try {
+      const preview = await invoke<string>('prune_cmd', { args: { ...args, options: { dryRun: true } } });
+      if (!window.confirm(`Nettoyage du dépôt :\n${preview}\n\nContinuer ?`)) {
+        setOutput(`Simulation : ${preview}`);
+        return;
+      }
+      const res = await invoke<string>('prune_cmd', { args });
+      setOutput(`Nettoyage terminé : ${res}`);
+    } catch (err) {
+      setOutput(`Erreur : ${String(err)}`);
+    } finally {
+      setLoading(false);
+    }
+  };
+
+  const handleUnlock = async () => {
+    if (!dest || !repoPassword) {
+      setOutput('Veuillez spécifier le dépôt local et son mot de passe.');
+      return;
+    }
+    setLoading(true);
+    setOutput('');
+
+    const args = { repoDir: dest, password: repoPassword };
+    try {
+      const stale = await invoke<number>('unlock_cmd', { ...args, force: false });
+      const locks = await invoke<RepoLockInfo[]>('list_locks_cmd', args);
+      if (locks.length === 0) {
+        setOutput(`${stale} verrou(s) obsolète(s) supprimé(s). Le dépôt n'est plus verrouillé.`);
+        return;
+      }
+      const held = locks
+        .map((l) => `${l.exclusive ? 'exclusif' : 'partagé'} — PID ${l.pid} sur ${l.hostname} depuis ${l.time}`)
+        .join('\n');
+      if (!window.confirm(`Verrous encore actifs :\n${held}\n\nNe les supprimez que si ces processus sont arrêtés. Forcer ?`)) {
+        setOutput(`${stale} verrou(s) obsolète(s) supprimé(s). Verrous actifs :\n${held}`);
+        return;
+      }
+      const forced = await invoke<number>('unlock_cmd', { ...args, force: true });
+      setOutput(`${stale + forced} verrou(s) supprimé(s).`);
+    } catch (err) {
+      setOutput(`Erreur : ${String(err)}`);
+    } finally {
+      setLoading(false);
+    }
+  };
+
+  const handleForget = async () => {
+    if (!dest || !repoPassword) {
+      setOutput('Veuillez spécifier le dépôt local et son mot de passe.');
+      return;
+    }
+    setLoading(true);
+    setOutput('');
+
+    const args = {
+      repoDir: dest,
+      password: repoPassword,
+      policy: { keepDaily, keepWeekly, keepMonthly, keepYearly, keepWithin: keepWithin || null },
+    };
+    const describe = (groups: ForgetGroup[]): string =>
+      groups
+        .map((g) => [
+          `${g.hostname} : ${g.paths.join(', ')}`,
+          ...g.snapshots.map((d) =>
+            `  ${d.reasons.length ? 'garder ' : 'oublier'} ${d.snapshot.time} ${d.snapshot.id.slice(0, 8)}` +
+            (d.reasons.length ? ` (${d.reasons.join(', ')})` : '')
+          ),
+        ].join('\n'))
+        .join('\n');
+    try {
+      const preview = await invoke<ForgetGroup[]>('forget_cmd', { args: { ...args, dryRun: true } });
+      const forgotten = preview.flatMap((g) => g.snapshots).filter((d) => d.reasons.length === 0).length;
+      if (forgotten === 0 || !window.confirm(`${forgotten} snapshot(s) seront oubliés. Continuer ?`)) {
+        setOutput(describe(preview));
+        return;
+      }
+      const groups = await invoke<ForgetGroup[]>('forget_cmd', { args });
+      setOutput(`${forgotten} snapshot(s) oubliés. Lancez le nettoyage pour libérer l'espace.\n${describe(groups)}`);
+    } catch (err) {
+      setOutput(`Erreur : ${String(err)}`);
+    } finally {
+      setLoading(false);
+    }
+  };
+
   const handleChunk = async () => {
     if (!source) {
       setOutput('Veuillez spécifier un fichier à découper.');
@@ -171,6 +468,25 @@ function App() {
             Parcourir
           </button>
         </div>
+        <input
+          className="input"
+          type="password"
+          placeholder="Mot de passe du dépôt"
+          value={repoPassword}
+          onChange={(e) => setRepoPassword(e.currentTarget.value)}
+        />
+      </section>
+
+      {/* Retention policy */}
+      <section className="section">
+        <h3>Rétention</h3>
+        <div className="input-group">
+          <input className="input" type="number" min={0} title="Quotidiens" value={keepDaily} onChange={(e)=>setKeepDaily(Number(e.target.value))} />
+          <input className="input" type="number" min={0} title="Hebdomadaires" value={keepWeekly} onChange={(e)=>setKeepWeekly(Number(e.target.value))} />
+          <input className="input" type="number" min={0} title="Mensuels" value={keepMonthly} onChange={(e)=>setKeepMonthly(Number(e.target.value))} />
+          <input className="input" type="number" min={0} title="Annuels" value={keepYearly} onChange={(e)=>setKeepYearly(Number(e.target.value))} />
+          <input className="input" placeholder="Garder les derniers (ex. 30d)" value={keepWithin} onChange={(e)=>setKeepWithin(e.target.value)} />
+        </div>
       </section>
 
       {/* SFTP configuration */}
@@ -182,9 +498,40 @@ function App() {
         </div>
         <div className="input-group">
           <input className="input" placeholder="Utilisateur" value={sftpUsername} onChange={(e)=>setSftpUsername(e.target.value)} />
-          <input className="input" type="password" placeholder="Mot de passe" value={sftpPassword} onChange={(e)=>setSftpPassword(e.target.value)} />
+          <select className="input" value={sftpAuthMethod} onChange={(e)=>setSftpAuthMethod(e.target.value as SftpAuthMethod)}>
+            <option value="password">Mot de passe</option>
+            <option value="keyFile">Clé privée</option>
+            <option value="agent">Agent SSH</option>
+            <option value="keyboardInteractive">Clavier interactif</option>
+          </select>
+        </div>
+        {(sftpAuthMethod === 'password' || sftpAuthMethod === 'keyboardInteractive') && (
+          <input className="input" type="password" placeholder={sftpAuthMethod === 'password' ? 'Mot de passe' : 'Réponse (mot de passe ou code)'} value={sftpPassword} onChange={(e)=>setSftpPassword(e.target.value)} />
+        )}
+        {sftpAuthMethod === 'keyFile' && (
+          <div className="input-group">
+            <input className="input" placeholder="Fichier de clé privée" value={sftpKeyPath} onChange={(e)=>setSftpKeyPath(e.target.value)} />
+            <button
+              className="button"
+              onClick={async () => {
+                const selected = await invoke<string | null>('open_file_dialog');
+                if (selected) setSftpKeyPath(selected);
+              }}
+            >
+              Parcourir
+            </button>
+            <input className="input" type="password" placeholder="Phrase de passe (optionnelle)" value={sftpPassphrase} onChange={(e)=>setSftpPassphrase(e.target.value)} />
+          </div>
+        )}
+        <div className="input-group">
+          <input className="input" placeholder="Chemin distant" value={sftpRemotePath} onChange={(e)=>setSftpRemotePath(e.target.value)} />
+          <select className="input" value={sftpResume} onChange={(e)=>setSftpResume(e.target.value as SftpResume)}>
+            <option value="restart">Transfert complet</option>
+            <option value="continue">Reprendre les transferts interrompus</option>
+            <option value="verify">Reprendre après vérification</option>
+          </select>
+          <input className="input" type="number" min={1} placeholder="Connexions parallèles" title="Connexions parallèles (sauvegarde chiffrée)" value={sftpStreams} onChange={(e)=>setSftpStreams(Math.max(1, Number(e.target.value)))} />
         </div>
-        <input className="input" placeholder="Chemin distant" value={sftpRemotePath} onChange={(e)=>setSftpRemotePath(e.target.value)} />
       </section>
 
       {/* Progress bar */}
@@ -202,6 +549,21 @@ function App() {
         <button className="button" disabled={loading} onClick={handleSftpBackup}>
           Sauvegarde SFTP
         </button>
+        <button className="button" disabled={loading} onClick={handleSftpRepoBackup}>
+          Sauvegarde chiffrée SFTP
+        </button>
+        <button className="button" disabled={loading} onClick={handleCheck}>
+          Vérifier le dépôt
+        </button>
+        <button className="button" disabled={loading} onClick={handleForget}>
+          Appliquer la rétention
+        </button>
+        <button className="button" disabled={loading} onClick={handlePrune}>
+          Nettoyer le dépôt
+        </button>
+        <button className="button" disabled={loading} onClick={handleUnlock}>
+          Déverrouiller le dépôt
+        </button>
         <button className="button" disabled={loading} onClick={handleChunk}>
           Découper en blocs
         </button>
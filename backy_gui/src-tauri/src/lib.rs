@@ -1,15 +1,20 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use tauri::Manager;
 use tauri_plugin_dialog::DialogExt;
-use backy_core::{backup_start, chunk_file, init_repo, save_blob, list_blobs, save_blob_local};
-use std::path::{Path, PathBuf}; // Added PathBuf for path manipulation
-use serde::Deserialize;
+use backy_core::{
+  backup_start, backup, check, chunk_file, forget, init_repo, open_repo, list_snapshots, prune,
+  remove_snapshot, restore, Repository, CheckOptions, CheckReport, ForgetGroup, HostKeyPolicy,
+  Lock, PruneOptions, RetentionPolicy, KeySlot, RestoreOptions, Resume, SftpAuth, SftpBackend, SftpClient, SftpError,
+  SftpPool, Snapshot, Uuid, KEEPALIVE_INTERVAL,
+};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
 use log::{info, error}; // Added for logging
 
-mod sftp;
-use sftp::SftpClient;
-
 #[tauri::command]
 fn backup_start_cmd(source: String) -> Result<String, String> {
   backup_start(&source).map_err(|e| e.to_string())
@@ -17,76 +22,279 @@ fn backup_start_cmd(source: String) -> Result<String, String> {
 
 #[tauri::command]
 fn chunk_file_cmd(path: String) -> Result<usize, String> {
-  chunk_file(&path)
-    .map(|chunks| chunks.len())
-    .map_err(|e| e.to_string())
+  let mut count = 0;
+  for chunk in chunk_file(&path).map_err(|e| e.to_string())? {
+    chunk.map_err(|e| e.to_string())?;
+    count += 1;
+  }
+  Ok(count)
 }
 
 #[tauri::command]
-fn init_repo_cmd() -> Result<String, String> {
-  init_repo()
-    .map(|(data_dir, _)| data_dir.to_string_lossy().into_owned())
+fn init_repo_cmd(password: String) -> Result<String, String> {
+  init_repo(&password)
+    .map(|repo| repo.location())
     .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn save_blob_cmd(blob: Vec<u8>) -> Result<String, String> {
-  save_blob(&blob)
+fn save_blob_cmd(password: String, blob: Vec<u8>) -> Result<String, String> {
+  open_repo(&password)
+    .and_then(|repo| {
+      // Blobs are buffered until flushed; report storage errors here rather
+      // than losing them when the repository is dropped. The lock keeps
+      // prune away until the blob is indexed.
+      let _lock = repo.lock_shared()?;
+      let id = repo.save_blob(&blob)?;
+      repo.flush()?;
+      Ok(id)
+    })
     .map(|id| id.to_string())
     .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn list_blobs_cmd() -> Result<Vec<String>, String> {
-  list_blobs()
+fn list_blobs_cmd(password: String) -> Result<Vec<String>, String> {
+  open_repo(&password)
+    .and_then(|repo| repo.list_blobs())
     .map(|ids| ids.into_iter().map(|id| id.to_string()).collect())
     .map_err(|e| e.to_string())
 }
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
 #[tauri::command]
-fn save_blob_local_cmd(path: String, dest_dir: String) -> Result<String, String> {
-  let metadata = std::fs::metadata(&path).map_err(|e| e.to_string())?;
-  
-  if metadata.is_file() {
-    // Handle file
-    let data = std::fs::read(&path).map_err(|e| e.to_string())?;
-    let filename = std::path::Path::new(&path)
-      .file_name()
-      .and_then(|n| n.to_str())
-      .unwrap_or("file")
-      .to_string();
-    save_blob_local(&data, &dest_dir, &filename).map_err(|e| e.to_string())?;
-    Ok("Fichier sauvegardé avec succès".to_string())
-  } else if metadata.is_dir() {
-    // Handle directory
-    let dir_name = std::path::Path::new(&path)
-      .file_name()
-      .and_then(|n| n.to_str())
-      .unwrap_or("directory")
-      .to_string();
-    let dest_path = std::path::Path::new(&dest_dir).join(&dir_name);
-    std::fs::create_dir_all(&dest_path).map_err(|e| e.to_string())?;
-    
-    // Copy all files recursively
-    for entry in std::fs::read_dir(&path).map_err(|e| e.to_string())? {
-      let entry = entry.map_err(|e| e.to_string())?;
-      let entry_path = entry.path();
-      if entry_path.is_file() {
-        let data = std::fs::read(&entry_path).map_err(|e| e.to_string())?;
-        let filename = entry_path
-          .file_name()
-          .and_then(|n| n.to_str())
-          .unwrap_or("file")
-          .to_string();
-        save_blob_local(&data, &dest_path.to_string_lossy(), &filename)
-          .map_err(|e| e.to_string())?;
-      }
-    }
-    Ok(format!("Dossier sauvegardé dans : {}", dest_path.to_string_lossy()))
-  } else {
-    Err("Le chemin spécifié n'est ni un fichier ni un dossier".to_string())
+fn list_snapshots_cmd(repo_dir: String, password: String) -> Result<Vec<Snapshot>, String> {
+  Repository::open(Path::new(&repo_dir), &password)
+    .and_then(|repo| list_snapshots(&repo))
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_keys_cmd(repo_dir: String, password: String) -> Result<Vec<KeySlot>, String> {
+  Repository::open(Path::new(&repo_dir), &password)
+    .and_then(|repo| repo.list_keys())
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn add_password_cmd(repo_dir: String, password: String, new_password: String) -> Result<String, String> {
+  Repository::open(Path::new(&repo_dir), &password)
+    .and_then(|repo| repo.add_password(&new_password))
+    .map(|id| id.to_string())
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn remove_key_cmd(repo_dir: String, password: String, key_id: String) -> Result<(), String> {
+  let key_id: Uuid = key_id.parse().map_err(|e| format!("Invalid key ID: {}", e))?;
+  Repository::open(Path::new(&repo_dir), &password)
+    .and_then(|repo| repo.remove_key(&key_id))
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn change_password_cmd(repo_dir: String, password: String, new_password: String) -> Result<(), String> {
+  Repository::open(Path::new(&repo_dir), &password)
+    .and_then(|mut repo| repo.change_password(&new_password))
+    .map_err(|e| e.to_string())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RestoreArgs {
+  repo_dir: String,
+  snapshot_id: String,
+  dest_dir: String,
+  password: String,
+  #[serde(default)]
+  options: RestoreOptions,
+}
+
+#[tauri::command]
+fn restore_cmd(args: RestoreArgs) -> Result<String, String> {
+  info!("Restore: Restoring snapshot {} from '{}' to '{}'", args.snapshot_id, args.repo_dir, args.dest_dir);
+  let snapshot_id: Uuid = args.snapshot_id.parse().map_err(|e| format!("Invalid snapshot ID: {}", e))?;
+  let summary = Repository::open(Path::new(&args.repo_dir), &args.password)
+    .and_then(|repo| restore(&repo, &snapshot_id, Path::new(&args.dest_dir), &args.options))
+    .map_err(|e| {
+      error!("Restore: Restore of snapshot {} failed: {}", args.snapshot_id, e);
+      e.to_string()
+    })?;
+  info!("Restore: Snapshot {} restored", args.snapshot_id);
+  Ok(format!(
+    "{} fichier(s) restauré(s), {} octet(s), {} ignoré(s)",
+    summary.files, summary.bytes, summary.skipped
+  ))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CheckArgs {
+  repo_dir: String,
+  password: String,
+  #[serde(default)]
+  options: CheckOptions,
+}
+
+#[tauri::command]
+fn check_cmd(args: CheckArgs) -> Result<CheckReport, String> {
+  info!("Check: Checking repository '{}'", args.repo_dir);
+  let report = Repository::open(Path::new(&args.repo_dir), &args.password)
+    .and_then(|repo| check(&repo, &args.options))
+    .map_err(|e| {
+      error!("Check: Check of '{}' failed: {}", args.repo_dir, e);
+      e.to_string()
+    })?;
+  info!("Check: {} problem(s) found in '{}'", report.problems.len(), args.repo_dir);
+  Ok(report)
+}
+
+#[tauri::command]
+fn remove_snapshot_cmd(repo_dir: String, password: String, snapshot_id: String) -> Result<(), String> {
+  let snapshot_id: Uuid = snapshot_id.parse().map_err(|e| format!("Invalid snapshot ID: {}", e))?;
+  Repository::open(Path::new(&repo_dir), &password)
+    .and_then(|repo| {
+      let _lock = repo.lock_exclusive()?;
+      remove_snapshot(&repo, &snapshot_id)
+    })
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_locks_cmd(repo_dir: String, password: String) -> Result<Vec<Lock>, String> {
+  Repository::open(Path::new(&repo_dir), &password)
+    .and_then(|repo| repo.list_locks())
+    .map_err(|e| e.to_string())
+}
+
+/// Remove stale locks, or every lock with `force`. Returns how many were removed.
+#[tauri::command]
+fn unlock_cmd(repo_dir: String, password: String, force: bool) -> Result<usize, String> {
+  Repository::open(Path::new(&repo_dir), &password)
+    .and_then(|repo| repo.remove_locks(force))
+    .map(|removed| removed.len())
+    .map_err(|e| e.to_string())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ForgetArgs {
+  repo_dir: String,
+  password: String,
+  policy: RetentionPolicy,
+  #[serde(default)]
+  dry_run: bool,
+}
+
+#[tauri::command]
+fn forget_cmd(args: ForgetArgs) -> Result<Vec<ForgetGroup>, String> {
+  info!("Forget: Applying retention policy to '{}' (dry run: {})", args.repo_dir, args.dry_run);
+  Repository::open(Path::new(&args.repo_dir), &args.password)
+    .and_then(|repo| forget(&repo, &args.policy, args.dry_run))
+    .map_err(|e| {
+      error!("Forget: Failed on '{}': {}", args.repo_dir, e);
+      e.to_string()
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PruneArgs {
+  repo_dir: String,
+  password: String,
+  #[serde(default)]
+  options: PruneOptions,
+}
+
+#[tauri::command]
+fn prune_cmd(args: PruneArgs) -> Result<String, String> {
+  info!("Prune: Pruning repository '{}' (dry run: {})", args.repo_dir, args.options.dry_run);
+  let summary = Repository::open(Path::new(&args.repo_dir), &args.password)
+    .and_then(|repo| prune(&repo, &args.options))
+    .map_err(|e| {
+      error!("Prune: Prune of '{}' failed: {}", args.repo_dir, e);
+      e.to_string()
+    })?;
+  Ok(format!(
+    "{} bloc(s) supprimé(s), {} conservé(s), {} pack(s) supprimé(s), {} réécrit(s), \
+     {} pack(s) réindexé(s), {} pack(s) illisible(s) conservé(s), \
+     {} fichier(s) temporaire(s), {} octet(s) libéré(s)",
+    summary.blobs_removed, summary.blobs_kept, summary.packs_deleted, summary.packs_repacked,
+    summary.packs_unindexed, summary.unreadable_packs.len(), summary.temp_files_deleted,
+    summary.bytes_freed
+  ))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BackupLocalArgs {
+  source: String,
+  dest_dir: String,
+  password: String,
+  #[serde(default)]
+  tags: Vec<String>,
+}
+
+#[tauri::command]
+fn backup_local_cmd(args: BackupLocalArgs) -> Result<String, String> {
+  info!("Local Backup: Backing up '{}' into repository '{}'", args.source, args.dest_dir);
+  let (snapshot, summary) = Repository::open_or_init(Path::new(&args.dest_dir), &args.password)
+    .and_then(|repo| backup(&repo, &[Path::new(&args.source)], args.tags))
+    .map_err(|e| {
+      error!("Local Backup: Backup of '{}' failed: {}", args.source, e);
+      e.to_string()
+    })?;
+  info!("Local Backup: Snapshot {} saved", snapshot.id);
+  Ok(format!(
+    "Snapshot {} : {} fichier(s), {} octet(s), {} nouveau(x) bloc(s)",
+    snapshot.id, summary.files, summary.bytes, summary.new_chunks
+  ))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BackupSftpArgs {
+  source: String,
+  host: String,
+  port: u16,
+  username: String,
+  auth: SftpAuth,
+  #[serde(default)]
+  trusted_fingerprint: Option<String>,
+  remote_path: String,
+  password: String,
+  #[serde(default)]
+  tags: Vec<String>,
+  /// Number of SFTP connections used to upload packs in parallel.
+  #[serde(default = "default_sftp_streams")]
+  streams: usize,
+}
+
+fn default_sftp_streams() -> usize {
+  4
+}
+
+#[tauri::command]
+fn backup_sftp_cmd(pool: tauri::State<'_, SftpPool>, args: BackupSftpArgs) -> Result<String, SftpCommandError> {
+  info!("SFTP Repository Backup: Backing up '{}' into {}:{}", args.source, args.host, args.remote_path);
+  let host_keys = HostKeyPolicy { accept_fingerprint: args.trusted_fingerprint.clone(), ..Default::default() };
+  let client = sftp_connect(&pool, &args.host, args.port, &args.username, &args.auth, args.trusted_fingerprint)?;
+  let mut backend = SftpBackend::new(client, &args.remote_path);
+  for _ in 1..args.streams {
+    // Extra upload streams are dedicated to this backup and not pooled.
+    let stream = SftpClient::connect(&args.host, args.port, &args.username, &args.auth, &host_keys)?;
+    backend.add_stream(Arc::new(stream));
   }
+  let (snapshot, summary) = Repository::open_or_init_with(Box::new(backend), &args.password)
+    .and_then(|repo| backup(&repo, &[Path::new(&args.source)], args.tags))
+    .map_err(|e| {
+      error!("SFTP Repository Backup: Backup of '{}' failed: {}", args.source, e);
+      SftpCommandError::other(e)
+    })?;
+  info!("SFTP Repository Backup: Snapshot {} saved", snapshot.id);
+  Ok(format!(
+    "Snapshot {} : {} fichier(s), {} octet(s), {} nouveau(x) bloc(s)",
+    snapshot.id, summary.files, summary.bytes, summary.new_chunks
+  ))
 }
 
 #[tauri::command]
@@ -107,61 +315,79 @@ async fn open_directory_dialog(app: tauri::AppHandle) -> Option<String> {
   rx.recv().unwrap()
 }
 
+/// Error returned by the SFTP commands. An unknown host key carries the host
+/// and fingerprint so the frontend can ask the user to trust it.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum SftpCommandError {
+  UnknownHostKey { host: String, fingerprint: String, message: String },
+  Other { message: String },
+}
+
+impl SftpCommandError {
+  fn other(err: impl std::fmt::Display) -> Self {
+    SftpCommandError::Other { message: err.to_string() }
+  }
+}
+
+impl From<SftpError> for SftpCommandError {
+  fn from(err: SftpError) -> Self {
+    let message = err.to_string();
+    match err {
+      SftpError::UnknownHostKey { host, fingerprint } => {
+        SftpCommandError::UnknownHostKey { host, fingerprint, message }
+      }
+      _ => SftpCommandError::Other { message },
+    }
+  }
+}
+
+/// Get a pooled SFTP connection, connecting if needed and logging failures.
+/// `trusted_fingerprint` is the host key fingerprint the user confirmed after
+/// a previous attempt failed with `SftpError::UnknownHostKey`.
+fn sftp_connect(
+  pool: &SftpPool,
+  host: &str,
+  port: u16,
+  username: &str,
+  auth: &SftpAuth,
+  trusted_fingerprint: Option<String>,
+) -> Result<Arc<SftpClient>, SftpError> {
+  let host_keys = HostKeyPolicy { accept_fingerprint: trusted_fingerprint, ..Default::default() };
+  pool.get(host, port, username, auth, &host_keys).inspect_err(|e| {
+    error!("SFTP: Connection to {}:{} failed: {}", host, port, e);
+  })
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct SftpBackupArgs {
   host: String,
   port: u16,
   username: String,
-  password: String,
+  auth: SftpAuth,
+  #[serde(default)]
+  trusted_fingerprint: Option<String>,
   local_path: String,
   remote_path: String,
+  #[serde(default)]
+  resume: Resume,
 }
 
 #[tauri::command]
-fn sftp_backup(args: SftpBackupArgs) -> Result<String, String> {
-  info!("SFTP Backup: Attempting SFTP connection to {}:{}", args.host, args.port);
-  
-  let client = SftpClient::new(&args.host, args.port, &args.username, &args.password)
-      .map_err(|e| {
-          error!("SFTP Backup: Connection failed: {}", e);
-          e.to_string()
-      })?;
-  
-  info!("SFTP Backup: Ensuring remote directory exists: {}", args.remote_path);
-  client.create_directory(&args.remote_path)
-      .map_err(|e| {
-          error!("SFTP Backup: Failed to create remote directory '{}': {}", args.remote_path, e);
-          e.to_string()
-      })?;
-  
-  let local_path = Path::new(&args.local_path);
-  let file_name = local_path.file_name().ok_or_else(|| {
-      let err_msg = format!("SFTP Backup: Invalid local path, could not extract filename: {}", args.local_path);
-      error!("{}", err_msg);
-      err_msg
-  })?.to_str().ok_or_else(|| {
-      let err_msg = format!("SFTP Backup: Filename from local path is not valid UTF-8: {}", args.local_path);
-      error!("{}", err_msg);
-      err_msg
-  })?;
-
-  let actual_remote_target_path = Path::new(&args.remote_path).join(file_name);
-  let actual_remote_target_path_str = actual_remote_target_path.to_str().ok_or_else(|| {
-    let err_msg = format!("SFTP Backup: Constructed remote path is not valid UTF-8: {}", actual_remote_target_path.display());
-    error!("{}", err_msg);
-    err_msg
-  })?;
-
-  info!("SFTP Backup: Uploading file {} to {}", args.local_path, actual_remote_target_path_str);
-  client.upload_file(local_path, actual_remote_target_path_str)
-      .map_err(|e| {
-          error!("SFTP Backup: File upload failed for '{}' to '{}': {}", args.local_path, actual_remote_target_path_str, e);
-          e.to_string()
-      })?;
-  
-  info!("SFTP Backup: Backup successful for {}", args.local_path);
-  Ok(format!("File '{}' backed up successfully to '{}'", file_name, args.remote_path))
+fn sftp_backup(pool: tauri::State<'_, SftpPool>, args: SftpBackupArgs) -> Result<String, SftpCommandError> {
+  info!("SFTP Backup: Uploading '{}' to {}:{}", args.local_path, args.host, args.remote_path);
+  let (remote, summary) = sftp_connect(&pool, &args.host, args.port, &args.username, &args.auth, args.trusted_fingerprint)
+    .and_then(|client| client.upload_into(Path::new(&args.local_path), &args.remote_path, args.resume))
+    .map_err(|e| {
+      error!("SFTP Backup: Upload of '{}' failed: {}", args.local_path, e);
+      SftpCommandError::from(e)
+    })?;
+  info!("SFTP Backup: Uploaded '{}' to '{}'", args.local_path, remote);
+  Ok(format!(
+    "'{}' sauvegardé vers '{}' : {} fichier(s), {} dossier(s), {} octet(s), {} octet(s) repris",
+    args.local_path, remote, summary.files, summary.dirs, summary.bytes, summary.resumed
+  ))
 }
 
 #[derive(Deserialize)]
@@ -170,27 +396,23 @@ struct SftpListDirectoryArgs {
     host: String,
     port: u16,
     username: String,
-    password: String,
+    auth: SftpAuth,
+    #[serde(default)]
+    trusted_fingerprint: Option<String>,
     remote_path: String,
 }
 
 #[tauri::command]
-fn sftp_list_directory(args: SftpListDirectoryArgs) -> Result<Vec<String>, String> {
-    info!("SFTP List Directory: Attempting connection to {}:{} for path '{}'", args.host, args.port, args.remote_path);
-    let client = SftpClient::new(&args.host, args.port, &args.username, &args.password)
-        .map_err(|e| {
-            error!("SFTP List Directory: Connection failed for {}:{}: {}", args.host, args.port, e);
-            e.to_string()
-        })?;
-    
-    let entries = client.list_directory(&args.remote_path)
-        .map_err(|e| {
-            error!("SFTP List Directory: Failed to list directory '{}': {}", args.remote_path, e);
-            e.to_string()
-        })?;
-    
-    info!("SFTP List Directory: Successfully listed directory '{}'", args.remote_path);
-    Ok(entries)
+fn sftp_list_directory(
+  pool: tauri::State<'_, SftpPool>,
+  args: SftpListDirectoryArgs,
+) -> Result<Vec<String>, SftpCommandError> {
+  sftp_connect(&pool, &args.host, args.port, &args.username, &args.auth, args.trusted_fingerprint)
+    .and_then(|client| client.list_directory(&args.remote_path))
+    .map_err(|e| {
+      error!("SFTP List Directory: Failed to list '{}': {}", args.remote_path, e);
+      SftpCommandError::from(e)
+    })
 }
 
 #[derive(Deserialize)]
@@ -199,31 +421,36 @@ struct SftpDownloadFileArgs {
     host: String,
     port: u16,
     username: String,
-    password: String,
+    auth: SftpAuth,
+    #[serde(default)]
+    trusted_fingerprint: Option<String>,
     remote_path: String,
     local_path: String,
+    #[serde(default)]
+    resume: Resume,
 }
 
+/// Download a remote file or a whole directory tree, optionally resuming
+/// partial local files.
 #[tauri::command]
-fn sftp_download_file(args: SftpDownloadFileArgs) -> Result<String, String> {
-    info!("SFTP Download File: Attempting to download '{}' from {}:{} to '{}'", args.remote_path, args.host, args.port, args.local_path);
-    let client = SftpClient::new(&args.host, args.port, &args.username, &args.password)
-        .map_err(|e| {
-            error!("SFTP Download File: Connection failed for {}:{}: {}", args.host, args.port, e);
-            e.to_string()
-        })?;
-
-    client.download_file(&args.remote_path, Path::new(&args.local_path))
-        .map_err(|e| {
-            error!("SFTP Download File: Failed to download '{}' to '{}': {}", args.remote_path, args.local_path, e);
-            e.to_string()
-        })?;
-
-    info!("SFTP Download File: Successfully downloaded '{}' to '{}'", args.remote_path, args.local_path);
-    Ok("File downloaded successfully".to_string())
+fn sftp_download_file(
+  pool: tauri::State<'_, SftpPool>,
+  args: SftpDownloadFileArgs,
+) -> Result<String, SftpCommandError> {
+  let summary = sftp_connect(&pool, &args.host, args.port, &args.username, &args.auth, args.trusted_fingerprint)
+    .and_then(|client| client.download_tree(&args.remote_path, Path::new(&args.local_path), args.resume))
+    .map_err(|e| {
+      error!("SFTP Download File: Failed to download '{}': {}", args.remote_path, e);
+      SftpCommandError::from(e)
+    })?;
+  info!("SFTP Download File: Downloaded '{}' to '{}'", args.remote_path, args.local_path);
+  Ok(format!(
+    "{} fichier(s), {} dossier(s), {} octet(s) téléchargé(s), {} octet(s) repris",
+    summary.files, summary.dirs, summary.bytes, summary.resumed
+  ))
 }
 
-
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
   tauri::Builder::default()
     .invoke_handler(tauri::generate_handler![
@@ -232,13 +459,36 @@ pub fn run() {
       init_repo_cmd,
       save_blob_cmd,
       list_blobs_cmd,
-      save_blob_local_cmd,
+      list_snapshots_cmd,
+      restore_cmd,
+      check_cmd,
+      remove_snapshot_cmd,
+      list_locks_cmd,
+      unlock_cmd,
+      forget_cmd,
+      prune_cmd,
+      list_keys_cmd,
+      add_password_cmd,
+      remove_key_cmd,
+      change_password_cmd,
+      backup_local_cmd,
+      backup_sftp_cmd,
       open_file_dialog,
       open_directory_dialog,
       sftp_backup,
       sftp_list_directory,
       sftp_download_file
     ])
+    .manage(SftpPool::new())
+    .setup(|app| {
+      // Keep pooled SFTP connections from being dropped while idle.
+      let handle = app.handle().clone();
+      std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(KEEPALIVE_INTERVAL.into()));
+        handle.state::<SftpPool>().keepalive();
+      });
+      Ok(())
+    })
     .plugin(tauri_plugin_dialog::init())
     .plugin(tauri_plugin_log::Builder::default()
       .level(log::LevelFilter::Info) // Ensure log level is set